@@ -0,0 +1,6 @@
+/// Tracks the class body currently being compiled so `this` and `super`
+/// can be validated against their surrounding class.
+#[derive(Debug, Clone, Default)]
+pub struct ClassState {
+    pub has_superclass: bool,
+}
@@ -1,25 +1,41 @@
-use crate::function::{Function, FunctionType};
+use crate::core::{Function, FunctionType};
 use super::local::Local;
+use super::loop_state::LoopState;
+use super::try_state::TryState;
 use super::upvalue::Upvalue;
 
 #[derive(Debug, Clone)]
 pub struct CompilerState {
-    function: Function,
-    function_type: FunctionType,
-    enclosing: Option<usize>,
-    scope_depth: usize,
-    locals: Vec<Local>,
-    local_count: usize,
-    upvalue_count: usize,
-    upvalues: Vec<Upvalue>
+    pub function: Function,
+    pub function_type: FunctionType,
+    pub enclosing: Option<usize>,
+    pub scope_depth: usize,
+    pub locals: Vec<Local>,
+    pub local_count: usize,
+    pub upvalues: Vec<Upvalue>,
+    /// Enclosing loops within this function, innermost last.
+    pub loops: Vec<LoopState>,
+    /// Enclosing `try` blocks within this function, innermost last.
+    pub tries: Vec<TryState>,
 }
 
 impl CompilerState {
-    pub fn function(&self) -> &Function {
-        &self.function
+    pub fn new(function: Function, function_type: FunctionType, enclosing: Option<usize>) -> Self {
+        // Slot zero holds the callee, or the receiver when compiling a method.
+        let receiver = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            _ => "",
+        };
+        CompilerState {
+            function,
+            function_type,
+            enclosing,
+            scope_depth: 0,
+            locals: vec![Local::new(receiver, 0)],
+            local_count: 1,
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+            tries: Vec::new(),
+        }
     }
-
-    pub fn function_mut(&mut self) -> &mut Function {
-        &mut self.function
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,59 @@
+use std::fmt;
+
+/// A single problem found while compiling, pointing at the token that
+/// caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub line: i32,
+    /// Byte offset and length of the offending token in the source.
+    pub start: usize,
+    pub length: usize,
+    pub location: Location,
+    /// Other places in the source that help explain the error.
+    pub labels: Vec<Label>,
+}
+
+/// A secondary note attached to a diagnostic, such as where a clashing
+/// variable was first declared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub message: String,
+    pub line: i32,
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Label {
+    pub fn new(message: &str, line: i32, start: usize, length: usize) -> Self {
+        Label {
+            message: String::from(message),
+            line,
+            start,
+            length,
+        }
+    }
+}
+
+/// Where in the token stream the error was reported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    /// At a token, holding its lexeme.
+    Lexeme(String),
+    /// At the end of the source.
+    End,
+    /// The scanner could not produce a token at all.
+    Scanner,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Location::Lexeme(lexeme) => {
+                write!(f, "[line {}] Error at '{}': {}", self.line, lexeme, self.message)
+            }
+            Location::End => write!(f, "[line {}] Error at end: {}", self.line, self.message),
+            Location::Scanner => write!(f, "[line {}] Error: {}", self.line, self.message),
+        }
+    }
+}
@@ -1,7 +1,32 @@
-use crate::token::Token;
+use crate::scanner::Token;
 
 #[derive(Debug, Clone)]
 pub struct Local {
-    name: Token,
-    depth: usize,
+    pub name: String,
+    pub depth: usize,
+    pub is_captured: bool,
+    /// False while the variable's own initializer is being compiled.
+    pub initialized: bool,
+    /// The name token of the declaration, if the local came from source.
+    pub declared_at: Option<Token>,
+}
+
+impl Local {
+    pub fn new(name: &str, depth: usize) -> Self {
+        Self {
+            name: String::from(name),
+            depth,
+            is_captured: false,
+            initialized: true,
+            declared_at: None,
+        }
+    }
+
+    pub fn uninitialized(name: &str, depth: usize, declared_at: Token) -> Self {
+        Self {
+            initialized: false,
+            declared_at: Some(declared_at),
+            ..Local::new(name, depth)
+        }
+    }
 }
@@ -0,0 +1,26 @@
+/// Tracks a loop being compiled so `break` and `continue` know where to
+/// jump and which locals to discard on the way.
+#[derive(Debug, Clone)]
+pub struct LoopState {
+    /// Where `continue` jumps back to: the increment clause of a `for`, or
+    /// the condition otherwise.
+    pub start: usize,
+    /// Locals declared deeper than this belong to the loop body.
+    pub scope_depth: usize,
+    /// `break` jumps waiting for the end of the loop to be known.
+    pub breaks: Vec<usize>,
+    /// How many `try` blocks were open when the loop began. Leaving any
+    /// opened since goes through their `finally`.
+    pub try_depth: usize,
+}
+
+impl LoopState {
+    pub fn new(start: usize, scope_depth: usize, try_depth: usize) -> Self {
+        Self {
+            start,
+            scope_depth,
+            breaks: Vec::new(),
+            try_depth,
+        }
+    }
+}
@@ -1,17 +1,52 @@
-use std::collections::HashSet;
 use std::fmt;
 use std::str::FromStr;
 
-use crate::core::{Closure, Function, FunctionType, Object, Value};
-use crate::vm::OpCode;
-use crate::scanner::{Scanner, Token, TokenKind};
+mod class_state;
+mod compiler_state;
+mod diagnostic;
+mod local;
+mod loop_state;
+mod try_state;
+mod upvalue;
 
-#[derive(Debug)]
-pub struct CompilerError(String);
+use crate::core::{Function, FunctionType, Object, Span, Value};
+use crate::vm::{Heap, ObjectId, OpCode, JUMP_SIZE};
+use crate::scanner::{Scanner, Token, TokenKind};
+use class_state::ClassState;
+use compiler_state::CompilerState;
+use local::Local;
+use loop_state::LoopState;
+use try_state::{Exit, TryState};
+use upvalue::Upvalue;
+
+// What one function can hold, set by the widest operands
+const MAX_CONSTANTS: usize = 1 << 24;
+const MAX_LOCALS: usize = 1 << 16;
+const MAX_UPVALUES: usize = u16::MAX as usize;
+const MAX_ARGS: usize = u16::MAX as usize;
+const MAX_ELEMENTS: usize = u16::MAX as usize;
+
+// Completion codes of a `try`, any above these number its exits
+const COMPLETED: f64 = 0.0;
+const THROWN: f64 = 1.0;
+const FIRST_EXIT: usize = 2;
+pub use diagnostic::{Diagnostic, Label, Location};
+
+/// Every diagnostic reported while compiling, in source order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompilerError {
+    pub diagnostics: Vec<Diagnostic>,
+}
 
 impl fmt::Display for CompilerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
     }
 }
 
@@ -27,6 +62,7 @@ enum Precedence {
     Factor,
     Unary,
     Call,
+    #[allow(dead_code)]
     Primary,
 }
 
@@ -50,75 +86,40 @@ impl ParseRule {
 
 struct Compiler<'a> {
     source: &'a str,
-    strings: &'a mut HashSet<String>,
+    heap: &'a mut Heap,
     current: Option<Token>,
     previous: Option<Token>,
     had_error: bool,
+    panic_mode: bool,
+    diagnostics: Vec<Diagnostic>,
     states: Vec<CompilerState>,
-    upvalues: Vec<Upvalue>,
-}
-
-
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
-struct Upvalue {
-    pub local: bool,
-    pub index: usize,
-}
-
-impl Upvalue {
-    fn new(local: bool, index: usize) -> Self {
-        Self { local, index }
-    }
+    classes: Vec<ClassState>,
 }
 
-#[derive(Debug, Clone)]
-struct CompilerState {
-    function: Function,
-    function_type: FunctionType,
-    enclosing: Option<usize>,
-    scope_depth: usize,
-    locals: Vec<Local>,
-    local_count: usize,
-    upvalue_count: usize,
-    upvalues: Vec<Upvalue>
-}
-
-#[derive(Debug, Clone)]
-struct Local {
-    name: Token,
-    depth: usize,
-}
 
 pub fn compile(
     source: &str,
     function: Function,
-    strings: &mut HashSet<String>,
-) -> Result<Function, CompilerError> {
+    heap: &mut Heap,
+) -> Result<ObjectId, CompilerError> {
     let mut scanner = Scanner::new(source);
-    let mut compiler = Compiler::new(source, function, strings);
+    let mut compiler = Compiler::new(source, function, heap);
     compiler.compile(&mut scanner)
 }
 
 impl<'a> Compiler<'a> {
-    fn new(source: &'a str, function: Function, strings: &'a mut HashSet<String>) -> Compiler<'a> {
-        let state: CompilerState = CompilerState {
-            function,
-            function_type: FunctionType::Script,
-            enclosing: None,
-            scope_depth: 0,
-            local_count: 0,
-            locals: Vec::new(),
-            upvalue_count: 0,
-            upvalues: Vec::with_capacity(u8::MAX as usize)
-        };
+    fn new(source: &'a str, function: Function, heap: &'a mut Heap) -> Compiler<'a> {
+        let state = CompilerState::new(function, FunctionType::Script, None);
         Compiler {
             source,
-            strings,
+            heap,
             current: None,
             previous: None,
             had_error: false,
+            panic_mode: false,
+            diagnostics: Vec::new(),
             states: vec![state],
-            upvalues: Vec::new(),
+            classes: Vec::new(),
         }
     }
 
@@ -135,14 +136,33 @@ impl<'a> Compiler<'a> {
     }
 
     fn emit_opcode(&mut self, op_code: OpCode) {
+        let span = self.previous_span();
+        self.emit_opcode_at(op_code, span);
+    }
+
+    /// Emits an instruction whose runtime errors should point at `span`
+    /// rather than at the token just consumed. Operands too big for the
+    /// instruction switch it to its long form.
+    fn emit_opcode_at(&mut self, op_code: OpCode, span: Span) {
         let line = self.current.as_ref().map_or(1, |t| t.line as u32);
         self.state_mut()
             .function
             .chunk
-            .write_chunk(op_code, line);
+            .write_chunk(op_code.widen(), line, span);
     }
 
-    fn compile(&mut self, scanner: &mut Scanner) -> Result<Function, CompilerError> {
+    fn emit_upvalues(&mut self, upvalues: &[(bool, usize)]) {
+        let line = self.current.as_ref().map_or(1, |t| t.line as u32);
+        self.state_mut().function.chunk.write_upvalues(upvalues, line);
+    }
+
+    fn previous_span(&self) -> Span {
+        self.previous
+            .as_ref()
+            .map_or_else(Span::default, |t| Span::new(t.start, t.length))
+    }
+
+    fn compile(&mut self, scanner: &mut Scanner) -> Result<ObjectId, CompilerError> {
         self.advance(scanner);
 
         loop {
@@ -152,23 +172,15 @@ impl<'a> Compiler<'a> {
             self.declaration(scanner);
         }
 
-        // emit return
-        self.emit_opcode(OpCode::Nil);
-        self.emit_opcode(OpCode::Return);
-
-        if self.had_error {
-            self.state()
-                .function
-                .chunk
-                .disassemble("Total Chunk")
-        }
+        self.emit_return();
 
         if !self.had_error {
-            Ok(self.state().function.clone())
+            let function = self.states.pop().unwrap().function;
+            Ok(self.heap.add_value(Object::Function(function)))
         } else {
-            Err(CompilerError(
-                "There was an error during compilation".to_string(),
-            ))
+            Err(CompilerError {
+                diagnostics: std::mem::take(&mut self.diagnostics),
+            })
         }
     }
 
@@ -181,8 +193,8 @@ impl<'a> Compiler<'a> {
             if let Some(token) = &self.current {
                 match &token.kind {
                     TokenKind::Error(err_msg) => {
-                        self.error_at_current(err_msg);
-                        self.had_error = true;
+                        let err_msg = err_msg.clone();
+                        self.error_at_current(&err_msg);
                     }
                     _ => break,
                 }
@@ -191,36 +203,79 @@ impl<'a> Compiler<'a> {
     }
 
     fn consume(&mut self, scanner: &mut Scanner, kind: TokenKind, message: &str) {
-        if let Some(current) = &self.current {
-            if current.kind == kind {
-                self.advance(scanner);
-            }
+        if self.check(kind) {
+            self.advance(scanner);
         } else {
             self.error_at_current(message);
         }
     }
 
-    fn error_at(&self, token: Token, message: &str) {
-        print!("[line {}] Error", token.line);
+    /// Records a diagnostic unless we are already recovering from one, in
+    /// which case it is most likely a cascade of the first.
+    fn error_at(&mut self, token: Token, message: &str) {
+        self.error_with_labels(token, message, Vec::new());
+    }
 
-        match token.kind {
-            TokenKind::Eof => {
-                println!(" at end of line: {}", message);
-                panic!()
-            }
-            TokenKind::Error(error) => println!(": {}: {}", error, message),
-            _ => {
-                let range = token.start..(token.start + token.length);
-                println!(": {} at '{}'", message, self.source.get(range).unwrap());
-            }
+    fn error_with_labels(&mut self, token: Token, message: &str, labels: Vec<Label>) {
+        if self.panic_mode {
+            return;
         }
+        self.panic_mode = true;
+        self.had_error = true;
+
+        let location = match token.kind {
+            TokenKind::Eof => Location::End,
+            TokenKind::Error(_) => Location::Scanner,
+            _ => Location::Lexeme(self.lexeme(&token).to_string()),
+        };
+        self.diagnostics.push(Diagnostic {
+            message: String::from(message),
+            line: token.line,
+            start: token.start,
+            length: token.length,
+            location,
+            labels,
+        });
     }
 
-    fn error_at_current(&self, message: &str) {
+    fn error_at_current(&mut self, message: &str) {
         let current = self.current.clone().unwrap();
         self.error_at(current, message);
     }
 
+    fn error(&mut self, message: &str) {
+        let previous = self.previous.clone().unwrap();
+        self.error_at(previous, message);
+    }
+
+    /// Skips tokens until a likely statement boundary so one mistake doesn't
+    /// bury the rest of the diagnostics.
+    fn synchronize(&mut self, scanner: &mut Scanner) {
+        self.panic_mode = false;
+
+        while !self.check(TokenKind::Eof) {
+            if self.previous.as_ref().is_some_and(|p| p.kind == TokenKind::Semicolon) {
+                return;
+            }
+            match self.current.as_ref().map(|c| &c.kind) {
+                Some(TokenKind::Class)
+                | Some(TokenKind::Fun)
+                | Some(TokenKind::Var)
+                | Some(TokenKind::For)
+                | Some(TokenKind::If)
+                | Some(TokenKind::While)
+                | Some(TokenKind::Match)
+                | Some(TokenKind::Try)
+                | Some(TokenKind::Throw)
+                | Some(TokenKind::Print)
+                | Some(TokenKind::Break)
+                | Some(TokenKind::Continue)
+                | Some(TokenKind::Return) => return,
+                _ => self.advance(scanner),
+            }
+        }
+    }
+
     fn parse_precedence(&mut self, precedence: Precedence, scanner: &mut Scanner) {
         self.advance(scanner);
         let can_assign = precedence <= Precedence::Assignment;
@@ -229,8 +284,8 @@ impl<'a> Compiler<'a> {
         if let Some(prefix_fn) = parse_rule.prefix {
             prefix_fn(self, scanner, can_assign);
         } else {
-            self.error_at_current("Expect expression");
-            return ();
+            self.error("Expect expression.");
+            return;
         }
 
         while precedence
@@ -247,8 +302,7 @@ impl<'a> Compiler<'a> {
         }
 
         if can_assign && self.matches(TokenKind::Equal, scanner) {
-            self.error_at_current("Invalid assignment target.");
-            self.expression(scanner);
+            self.error("Invalid assignment target.");
         }
     }
 
@@ -294,12 +348,25 @@ impl<'a> Compiler<'a> {
                 ParseRule::new(Some(Compiler::variable), None, Precedence::None)
             }
             TokenKind::String => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+            TokenKind::Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
+            TokenKind::LeftBrace => ParseRule::new(Some(Compiler::map), None, Precedence::None),
+            TokenKind::LeftBracket => ParseRule::new(
+                Some(Compiler::list),
+                Some(Compiler::subscript),
+                Precedence::Call,
+            ),
+            TokenKind::This => ParseRule::new(Some(Compiler::this), None, Precedence::None),
+            TokenKind::Super => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
             _ => ParseRule::new(None, None, Precedence::None),
         }
     }
 
+    fn check(&self, kind: TokenKind) -> bool {
+        self.current.as_ref().is_some_and(|c| c.kind == kind)
+    }
+
     fn matches(&mut self, kind: TokenKind, scanner: &mut Scanner) -> bool {
-        if !self.current.as_ref().map_or(false, |c| c.kind == kind) {
+        if !self.check(kind) {
             return false;
         }
         self.advance(scanner);
@@ -307,13 +374,86 @@ impl<'a> Compiler<'a> {
     }
 
     fn declaration(&mut self, scanner: &mut Scanner) {
-        if self.matches(TokenKind::Fun, scanner) {
+        if self.matches(TokenKind::Class, scanner) {
+            self.class_declaration(scanner);
+        } else if self.matches(TokenKind::Fun, scanner) {
             self.fun_declaration(scanner);
         } else if self.matches(TokenKind::Var, scanner) {
             self.var_declaration(scanner);
         } else {
             self.statement(scanner)
         }
+
+        if self.panic_mode {
+            self.synchronize(scanner);
+        }
+    }
+
+    fn class_declaration(&mut self, scanner: &mut Scanner) {
+        self.consume(scanner, TokenKind::Identifier, "Expect class name.");
+        let class_token = self.previous.clone().unwrap();
+        let class_name = self.lexeme(&class_token);
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_opcode(OpCode::Class(name_constant));
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState::default());
+
+        if self.matches(TokenKind::Less, scanner) {
+            self.consume(scanner, TokenKind::Identifier, "Expect superclass name.");
+            let superclass_name = self.lexeme(self.previous.as_ref().unwrap());
+            if superclass_name == class_name {
+                let label = Label::new(
+                    "class declared here",
+                    class_token.line,
+                    class_token.start,
+                    class_token.length,
+                );
+                let superclass = self.previous.clone().unwrap();
+                self.error_with_labels(superclass, "A class can't inherit from itself.", vec![label]);
+            }
+
+            // The superclass stays behind in a hidden `super` local, so methods
+            // capture the class itself rather than looking it up by name
+            self.named_variable(superclass_name, scanner, false);
+            self.begin_scope();
+            let scope_depth = self.scope_depth();
+            self.state_mut().locals.push(Local::new("super", scope_depth));
+            self.state_mut().local_count += 1;
+
+            self.named_variable(class_name, scanner, false);
+            self.emit_opcode(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back onto the stack so methods can be bound to it
+        self.named_variable(class_name, scanner, false);
+        self.consume(scanner, TokenKind::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.method(scanner);
+        }
+        self.consume(scanner, TokenKind::RightBrace, "Expect '}' after class body.");
+        self.emit_opcode(OpCode::Pop);
+
+        if self.classes.pop().is_some_and(|class| class.has_superclass) {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self, scanner: &mut Scanner) {
+        self.consume(scanner, TokenKind::Identifier, "Expect method name.");
+        let name = self.lexeme(self.previous.as_ref().unwrap());
+        let constant = self.identifier_constant(name);
+
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(scanner, function_type, constant);
+        self.emit_opcode(OpCode::Method(constant));
     }
 
     fn fun_declaration(&mut self, scanner: &mut Scanner) {
@@ -323,26 +463,36 @@ impl<'a> Compiler<'a> {
         self.define_variable(global);
     }
 
-    fn init_state(&mut self, function: Function) {
+    fn init_state(&mut self, function: Function, function_type: FunctionType) {
         let enclosing = Some(self.states.len() - 1);
-        let state: CompilerState = CompilerState {
-            function,
-            function_type: FunctionType::Function,
-            enclosing,
-            scope_depth: 0,
-            local_count: 0,
-            locals: Vec::new(),
-            upvalue_count: 0,
-            upvalues: Vec::new()
-        };
+        let state = CompilerState::new(function, function_type, enclosing);
         self.states.push(state);
     }
 
-    fn end_state(&mut self) -> Result<Function, CompilerError> {
-        self.emit_opcode(OpCode::Nil);
-        self.emit_opcode(OpCode::Return);
+    fn end_state(&mut self) -> Result<(Function, Vec<Upvalue>), CompilerError> {
+        self.emit_return();
         let state = self.states.pop().unwrap();
-        Ok(state.function)
+        Ok((state.function, state.upvalues))
+    }
+
+    fn emit_return(&mut self) {
+        // Initializers always hand back the instance sitting in slot zero
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_opcode(OpCode::GetLocal(0));
+        } else {
+            self.emit_opcode(OpCode::Nil);
+        }
+        self.return_value();
+    }
+
+    /// Returns the value on top of the stack, running any `finally` blocks
+    /// in the way first.
+    fn return_value(&mut self) {
+        if self.state().tries.is_empty() {
+            self.emit_opcode(OpCode::Return);
+        } else {
+            self.exit_try(Exit::Return);
+        }
     }
 
     fn function(
@@ -351,24 +501,13 @@ impl<'a> Compiler<'a> {
         function_type: FunctionType,
         constant_index: usize,
     ) {
-        let constant = self
-            .state()
-            .function
-            .chunk
-            .constants
-            .get(constant_index)
-            .unwrap();
-        let name = match constant {
-            Value::Object(object) => {
-                match object {
-                    Object::String(string) => string,
-                    _ => "Undefined"
-                }
-            }
-            _ => "Undefined",
+        let name = match self.state().function.chunk.constants.get(constant_index) {
+            Some(Value::Object(id)) => self.heap.get_string(id).cloned(),
+            _ => None,
         };
-        let function = Function::new(&name, function_type);
-        self.init_state(function);
+        let name = name.unwrap_or_else(|| String::from("Undefined"));
+        let function = Function::new(&name, function_type.clone());
+        self.init_state(function, function_type);
         self.begin_scope();
 
         // Compile the parameter list
@@ -379,12 +518,13 @@ impl<'a> Compiler<'a> {
         );
 
         // Parse parameters
-        if !self.matches(TokenKind::RightParen, scanner) {
+        if !self.check(TokenKind::RightParen) {
             loop {
                 self.state_mut().function.arity += 1;
 
-                if self.state().function.arity > 255 {
-                    self.error_at_current("Cannot have more than 255 parameters.");
+                if self.state().function.arity > MAX_ARGS {
+                    let message = format!("Cannot have more than {} parameters.", MAX_ARGS);
+                    self.error_at_current(&message);
                 }
                 let index = self.parse_variable("Expect parameter name.", scanner);
                 self.define_variable(index);
@@ -408,20 +548,14 @@ impl<'a> Compiler<'a> {
         self.block(scanner);
 
         match self.end_state() {
-            Ok(function) => {
-                let upvalue_count = function.upvalue_count;
-                let closure = Closure::new(function);
-                let index = self.add_constant(Value::Object(Object::Closure(closure)));
+            Ok((function, upvalues)) => {
+                let function = self.heap.add_value(Object::Function(function));
+                let index = self.make_constant(Value::Object(function));
                 self.emit_opcode(OpCode::Closure(index));
 
-                for i in 0..upvalue_count {
-                    let upvalue = self.upvalues[i].clone();
-                    if upvalue.local {
-                        self.emit_opcode(OpCode::LocalValue(upvalue.index))
-                    } else {
-                        self.emit_opcode(OpCode::Upvalue(upvalue.index))
-                    }
-                }
+                let upvalues: Vec<(bool, usize)> =
+                    upvalues.iter().map(|upvalue| (upvalue.local, upvalue.index)).collect();
+                self.emit_upvalues(&upvalues);
             }
             Err(e) => {
                 let message = format!("There was a problem compiling the function {}", e);
@@ -436,11 +570,7 @@ impl<'a> Compiler<'a> {
         if self.matches(TokenKind::Equal, scanner) {
             self.expression(scanner);
         } else {
-            let line = self.current.as_ref().unwrap().line as u32;
-            self.state_mut()
-                .function
-                .chunk
-                .write_chunk(OpCode::Nil, line);
+            self.emit_opcode(OpCode::Nil);
         }
 
         self.consume(
@@ -465,8 +595,8 @@ impl<'a> Compiler<'a> {
 
         self.declare_variable();
 
-        let identifier = self.previous.as_ref().unwrap().clone();
-        self.identifier_constant(&identifier)
+        let identifier = self.lexeme(self.previous.as_ref().unwrap());
+        self.identifier_constant(identifier)
     }
 
     fn declare_variable(&mut self) {
@@ -475,29 +605,44 @@ impl<'a> Compiler<'a> {
         }
 
         let name = self.previous.as_ref().unwrap().clone();
-        let state = self.state();
-
-        for local in &state.locals {
-            if local.depth < state.scope_depth {
-                break;
-            }
-
-            if local.name == name {
-                // TODO: Reimplement
-                // self.error_at_current("Variable with this name already declared in this scope.")
-            }
+        let lexeme = self.lexeme(&name);
+        let scope_depth = self.scope_depth();
+        let previous = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth >= scope_depth)
+            .find(|local| local.name == lexeme)
+            .and_then(|local| local.declared_at.clone());
+        if let Some(previous) = previous {
+            let label = Label::new(
+                "previously declared here",
+                previous.line,
+                previous.start,
+                previous.length,
+            );
+            self.error_with_labels(
+                name.clone(),
+                "Already a variable with this name in this scope.",
+                vec![label],
+            );
         }
 
         self.add_local(name);
     }
 
     fn add_local(&mut self, name: Token) {
+        if self.state().local_count == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
         let scope_depth = self.scope_depth();
+        let lexeme = &self.source[name.as_range()];
         self.state_mut().local_count += 1;
-        self.state_mut().locals.push(Local {
-            name,
-            depth: scope_depth,
-        })
+        self.state_mut()
+            .locals
+            .push(Local::uninitialized(lexeme, scope_depth, name))
     }
 
     fn define_variable(&mut self, index: usize) {
@@ -509,10 +654,28 @@ impl<'a> Compiler<'a> {
         self.emit_opcode(OpCode::DefineGlobal(index));
     }
 
-    fn identifier_constant(&mut self, token: &Token) -> usize {
-        let source = self.source.get((token.start)..(token.start + token.length));
-        let identifier = String::from(source.unwrap());
-        self.add_constant(Value::Object(Object::String(identifier)))
+    fn lexeme(&self, token: &Token) -> &'a str {
+        &self.source[token.as_range()]
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let id = self.heap.intern(name);
+        self.make_constant(Value::Object(id))
+    }
+
+    /// Adds a constant an instruction will refer to by index.
+    fn make_constant(&mut self, constant: Value) -> usize {
+        let index = self.add_constant(constant);
+        if index >= MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        index
+    }
+
+    fn emit_constant(&mut self, constant: Value) {
+        let index = self.make_constant(constant);
+        self.emit_opcode(OpCode::Constant(index));
     }
 
     fn add_constant(&mut self, constant: Value) -> usize {
@@ -527,7 +690,10 @@ impl<'a> Compiler<'a> {
             return;
         }
         let local_index = self.state().local_count - 1;
-        self.state_mut().locals[local_index].depth = self.scope_depth();
+        let scope_depth = self.scope_depth();
+        let local = &mut self.state_mut().locals[local_index];
+        local.depth = scope_depth;
+        local.initialized = true;
     }
 
     fn statement(&mut self, scanner: &mut Scanner) {
@@ -539,8 +705,18 @@ impl<'a> Compiler<'a> {
             self.if_statement(scanner);
         } else if self.matches(TokenKind::While, scanner) {
             self.while_statement(scanner);
+        } else if self.matches(TokenKind::Match, scanner) {
+            self.match_statement(scanner);
+        } else if self.matches(TokenKind::Try, scanner) {
+            self.try_statement(scanner);
+        } else if self.matches(TokenKind::Throw, scanner) {
+            self.throw_statement(scanner);
         } else if self.matches(TokenKind::Return, scanner) {
             self.return_statement(scanner);
+        } else if self.matches(TokenKind::Break, scanner) {
+            self.break_statement(scanner);
+        } else if self.matches(TokenKind::Continue, scanner) {
+            self.continue_statement(scanner);
         } else if self.matches(TokenKind::LeftBrace, scanner) {
             self.begin_scope();
             self.block(scanner);
@@ -582,17 +758,297 @@ impl<'a> Compiler<'a> {
 
     fn return_statement(&mut self, scanner: &mut Scanner) {
         if self.matches(TokenKind::Semicolon, scanner) {
-            self.emit_opcode(OpCode::Nil);
-            self.emit_opcode(OpCode::Return);
+            self.emit_return();
         } else {
+            if self.state().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
             self.expression(scanner);
             self.consume(
                 scanner,
                 TokenKind::Semicolon,
                 "Expect a ';' after a return value.",
             );
-            self.emit_opcode(OpCode::Return);
+            self.return_value();
+        }
+    }
+
+    /// Compiles `match (subject) { case a, b => ... default => ... }` as a
+    /// chain of comparisons against the subject, which lives in a hidden
+    /// local for the length of the statement.
+    fn match_statement(&mut self, scanner: &mut Scanner) {
+        self.begin_scope();
+        self.consume(scanner, TokenKind::LeftParen, "Expect '(' after 'match'.");
+        self.expression(scanner);
+        self.consume(scanner, TokenKind::RightParen, "Expect ')' after match subject.");
+        self.consume(scanner, TokenKind::LeftBrace, "Expect '{' before match arms.");
+
+        let subject = self.add_hidden_local();
+
+        let mut seen: Vec<(Value, Token)> = Vec::new();
+        let mut end_jumps = Vec::new();
+        let mut after_catch_all = false;
+        while self.matches(TokenKind::Case, scanner) || self.matches(TokenKind::Default, scanner) {
+            if after_catch_all {
+                self.error("A match can't have arms after a catch-all.");
+            }
+            let catch_all = if self.previous.as_ref().is_some_and(|t| t.kind == TokenKind::Default) {
+                true
+            } else if self.is_wildcard() {
+                self.advance(scanner);
+                true
+            } else {
+                false
+            };
+
+            if catch_all {
+                self.consume(scanner, TokenKind::EqualGreater, "Expect '=>' before arm body.");
+                self.statement(scanner);
+                after_catch_all = true;
+                continue;
+            }
+
+            // Each value that matches skips the rest straight to the body
+            let mut matched = Vec::new();
+            loop {
+                self.emit_opcode(OpCode::GetLocal(subject));
+                if let Some((value, token)) = self.case_value(scanner) {
+                    let previous = seen
+                        .iter()
+                        .find(|(seen, _)| *seen == value)
+                        .map(|(_, token)| token.clone());
+                    match previous {
+                        Some(previous) => {
+                            let label = Label::new(
+                                "first used here",
+                                previous.line,
+                                previous.start,
+                                previous.length,
+                            );
+                            self.error_with_labels(token.clone(), "Duplicate case value.", vec![label]);
+                        }
+                        None => seen.push((value, token.clone())),
+                    }
+                    self.emit_opcode_at(OpCode::Equal, Span::new(token.start, token.length));
+                }
+                if !self.matches(TokenKind::Comma, scanner) {
+                    break;
+                }
+                let else_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+                matched.push(self.emit_jump(OpCode::Jump(0)));
+                self.patch_jump(else_jump);
+                self.emit_opcode(OpCode::Pop);
+            }
+
+            let next_arm = self.emit_jump(OpCode::JumpIfFalse(0));
+            for jump in matched {
+                self.patch_jump(jump);
+            }
+            self.emit_opcode(OpCode::Pop);
+            self.consume(scanner, TokenKind::EqualGreater, "Expect '=>' before arm body.");
+            self.statement(scanner);
+            end_jumps.push(self.emit_jump(OpCode::Jump(0)));
+
+            self.patch_jump(next_arm);
+            self.emit_opcode(OpCode::Pop);
+        }
+        self.consume(scanner, TokenKind::RightBrace, "Expect '}' after match arms.");
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    /// Compiles `try { } catch (e) { } finally { }`, where either `catch` or
+    /// `finally` may be left out.
+    fn try_statement(&mut self, scanner: &mut Scanner) {
+        self.begin_scope();
+        self.emit_opcode(OpCode::Nil);
+        let value = self.add_hidden_local();
+        self.emit_constant(Value::Number(COMPLETED));
+        let completion = self.add_hidden_local();
+        let scope_depth = self.scope_depth();
+        self.state_mut().tries.push(TryState::new(scope_depth, completion, value));
+
+        self.consume(scanner, TokenKind::LeftBrace, "Expect '{' after 'try'.");
+        let handler = self.emit_jump(OpCode::Try(0));
+        self.begin_scope();
+        self.block(scanner);
+        self.end_scope();
+        self.emit_opcode(OpCode::PopHandler);
+        let mut completed = vec![self.emit_jump(OpCode::Jump(0))];
+
+        // Anything thrown arrives here on top of the stack
+        self.patch_jump(handler);
+        let has_catch = self.matches(TokenKind::Catch, scanner);
+        if has_catch {
+            self.begin_scope();
+            self.consume(scanner, TokenKind::LeftParen, "Expect '(' after 'catch'.");
+            self.consume(scanner, TokenKind::Identifier, "Expect exception variable name.");
+            self.declare_variable();
+            self.mark_initialized();
+            self.consume(scanner, TokenKind::RightParen, "Expect ')' after exception variable.");
+            self.consume(scanner, TokenKind::LeftBrace, "Expect '{' before catch body.");
+
+            let rethrow = self.emit_jump(OpCode::Try(0));
+            self.begin_scope();
+            self.block(scanner);
+            self.end_scope();
+            self.emit_opcode(OpCode::PopHandler);
+            let captured = self.state().locals.last().is_some_and(|local| local.is_captured);
+            self.end_scope();
+            completed.push(self.emit_jump(OpCode::Jump(0)));
+
+            // Thrown from the catch body, on top of the caught value
+            self.patch_jump(rethrow);
+            self.emit_opcode(OpCode::SetLocal(value));
+            self.emit_opcode(OpCode::Pop);
+            self.emit_opcode(if captured { OpCode::CloseUpvalue } else { OpCode::Pop });
+        } else {
+            self.emit_opcode(OpCode::SetLocal(value));
+            self.emit_opcode(OpCode::Pop);
+        }
+        self.emit_constant(Value::Number(THROWN));
+        self.emit_opcode(OpCode::SetLocal(completion));
+        self.emit_opcode(OpCode::Pop);
+
+        for jump in completed {
+            self.patch_jump(jump);
+        }
+        let state = self.state_mut().tries.pop().unwrap();
+        for (_, jump) in &state.exits {
+            self.patch_jump(*jump);
+        }
+        if self.matches(TokenKind::Finally, scanner) {
+            self.consume(scanner, TokenKind::LeftBrace, "Expect '{' after 'finally'.");
+            self.begin_scope();
+            self.block(scanner);
+            self.end_scope();
+        } else if !has_catch {
+            self.error_at_current("Expect 'catch' or 'finally' after try block.");
         }
+
+        // Carry on with however the protected code finished
+        self.if_completion(completion, THROWN, |compiler| {
+            compiler.emit_opcode(OpCode::GetLocal(value));
+            compiler.emit_opcode(OpCode::Rethrow);
+        });
+        for (i, (exit, _)) in state.exits.iter().enumerate() {
+            self.if_completion(completion, (FIRST_EXIT + i) as f64, |compiler| match exit {
+                Exit::Return => {
+                    compiler.emit_opcode(OpCode::GetLocal(value));
+                    compiler.return_value();
+                }
+                Exit::Break => compiler.emit_break(),
+                Exit::Continue => compiler.emit_continue(),
+            });
+        }
+        self.end_scope();
+    }
+
+    /// Emits `then` to run only when the completion in `slot` is `code`.
+    fn if_completion(&mut self, slot: usize, code: f64, then: impl FnOnce(&mut Self)) {
+        self.emit_opcode(OpCode::GetLocal(slot));
+        self.emit_constant(Value::Number(code));
+        self.emit_opcode(OpCode::Equal);
+        let skip = self.emit_jump(OpCode::JumpIfFalse(0));
+        self.emit_opcode(OpCode::Pop);
+        then(self);
+        self.patch_jump(skip);
+        self.emit_opcode(OpCode::Pop);
+    }
+
+    /// Leaves the innermost `try` for its `finally`, recording `exit` to be
+    /// taken once that has run. A returned value is on top of the stack.
+    fn exit_try(&mut self, exit: Exit) {
+        let state = self.state().tries.last().unwrap();
+        let (scope_depth, completion, value) = (state.scope_depth, state.completion, state.value);
+        let code = FIRST_EXIT + state.exits.len();
+
+        if exit == Exit::Return {
+            self.emit_opcode(OpCode::SetLocal(value));
+            self.emit_opcode(OpCode::Pop);
+        }
+        self.discard_locals(scope_depth);
+        self.emit_opcode(OpCode::PopHandler);
+        self.emit_constant(Value::Number(code as f64));
+        self.emit_opcode(OpCode::SetLocal(completion));
+        self.emit_opcode(OpCode::Pop);
+        let jump = self.emit_jump(OpCode::Jump(0));
+        self.state_mut().tries.last_mut().unwrap().exits.push((exit, jump));
+    }
+
+    fn throw_statement(&mut self, scanner: &mut Scanner) {
+        let span = self.previous_span();
+        self.expression(scanner);
+        // Emitted before the ';' is consumed so the line is the throw's own
+        self.emit_opcode_at(OpCode::Throw, span);
+        self.consume(scanner, TokenKind::Semicolon, "Expect ';' after thrown value.");
+    }
+
+    /// Declares a local for a value the compiler keeps on the stack, one
+    /// that can't be named in the source.
+    fn add_hidden_local(&mut self) -> usize {
+        if self.local_count() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+        }
+        let slot = self.local_count();
+        let scope_depth = self.scope_depth();
+        self.state_mut().locals.push(Local::new("", scope_depth));
+        self.state_mut().local_count += 1;
+        slot
+    }
+
+    /// `case _` matches anything, like `default`.
+    fn is_wildcard(&self) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|token| token.kind == TokenKind::Identifier && self.lexeme(token) == "_")
+    }
+
+    /// Compiles a case's literal, returning its value and token so
+    /// duplicates can be reported.
+    fn case_value(&mut self, scanner: &mut Scanner) -> Option<(Value, Token)> {
+        self.advance(scanner);
+        let mut token = self.previous.clone().unwrap();
+        let mut sign = 1.0;
+        if token.kind == TokenKind::Minus && self.check(TokenKind::Number) {
+            self.advance(scanner);
+            let number = self.previous.clone().unwrap();
+            // Report a negative number as a single span
+            token = Token {
+                length: number.start + number.length - token.start,
+                start: token.start,
+                ..number
+            };
+            sign = -1.0;
+        }
+
+        let value = match token.kind {
+            TokenKind::Nil => Value::Nil,
+            TokenKind::True => Value::Bool(true),
+            TokenKind::False => Value::Bool(false),
+            TokenKind::String => {
+                let string = &self.source[token.start + 1..token.start + token.length - 1];
+                Value::Object(self.heap.intern(string))
+            }
+            TokenKind::Number => {
+                let number = self.lexeme(self.previous.as_ref().unwrap());
+                Value::Number(sign * f64::from_str(number).unwrap_or_default())
+            }
+            _ => {
+                self.error("Expect a literal or '_' after 'case'.");
+                return None;
+            }
+        };
+        match value {
+            Value::Nil => self.emit_opcode(OpCode::Nil),
+            Value::Bool(true) => self.emit_opcode(OpCode::True),
+            Value::Bool(false) => self.emit_opcode(OpCode::False),
+            _ => self.emit_constant(value),
+        }
+        Some((value, token))
     }
 
     fn while_statement(&mut self, scanner: &mut Scanner) {
@@ -609,12 +1065,13 @@ impl<'a> Compiler<'a> {
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
 
         self.emit_opcode(OpCode::Pop);
-        self.statement(scanner);
+        self.loop_body(scanner, loop_start);
 
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_opcode(OpCode::Pop);
+        self.patch_breaks();
     }
 
     fn for_statement(&mut self, scanner: &mut Scanner) {
@@ -665,7 +1122,7 @@ impl<'a> Compiler<'a> {
             self.patch_jump(body_jump);
         }
 
-        self.statement(scanner);
+        self.loop_body(scanner, loop_start);
 
         self.emit_loop(loop_start);
 
@@ -673,35 +1130,113 @@ impl<'a> Compiler<'a> {
             self.patch_jump(exit_jump);
             self.emit_opcode(OpCode::Pop);
         }
+        self.patch_breaks();
 
         self.end_scope();
     }
 
+    /// Compiles a loop's body with `continue` going to `start`. The loop
+    /// stays on the stack until `patch_breaks` sees where it ends.
+    fn loop_body(&mut self, scanner: &mut Scanner, start: usize) {
+        let scope_depth = self.scope_depth();
+        let try_depth = self.state().tries.len();
+        self.state_mut().loops.push(LoopState::new(start, scope_depth, try_depth));
+        self.statement(scanner);
+    }
+
+    fn patch_breaks(&mut self) {
+        let state = self.state_mut().loops.pop().unwrap();
+        for jump in state.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self, scanner: &mut Scanner) {
+        let in_loop = !self.state().loops.is_empty();
+        if !in_loop {
+            self.error("Can't use 'break' outside of a loop.");
+        }
+        self.consume(scanner, TokenKind::Semicolon, "Expect ';' after 'break'.");
+        if in_loop {
+            self.emit_break();
+        }
+    }
+
+    fn continue_statement(&mut self, scanner: &mut Scanner) {
+        let in_loop = !self.state().loops.is_empty();
+        if !in_loop {
+            self.error("Can't use 'continue' outside of a loop.");
+        }
+        self.consume(scanner, TokenKind::Semicolon, "Expect ';' after 'continue'.");
+        if in_loop {
+            self.emit_continue();
+        }
+    }
+
+    /// Jumps out of the innermost loop, by way of any `finally` blocks
+    /// opened inside it.
+    fn emit_break(&mut self) {
+        let state = self.state().loops.last().unwrap();
+        if self.state().tries.len() > state.try_depth {
+            self.exit_try(Exit::Break);
+            return;
+        }
+        self.discard_locals(state.scope_depth);
+        let jump = self.emit_jump(OpCode::Jump(0));
+        self.state_mut().loops.last_mut().unwrap().breaks.push(jump);
+    }
+
+    fn emit_continue(&mut self) {
+        let state = self.state().loops.last().unwrap();
+        if self.state().tries.len() > state.try_depth {
+            self.exit_try(Exit::Continue);
+            return;
+        }
+        let start = state.start;
+        self.discard_locals(state.scope_depth);
+        self.emit_loop(start);
+    }
+
+    /// Emits the pops `end_scope` would for every local deeper than
+    /// `depth`, without forgetting them, for jumps out of those scopes.
+    fn discard_locals(&mut self, depth: usize) {
+        for i in (0..self.local_count()).rev() {
+            let local = &self.state().locals[i];
+            if local.depth <= depth {
+                break;
+            }
+            if local.is_captured {
+                self.emit_opcode(OpCode::CloseUpvalue);
+            } else {
+                self.emit_opcode(OpCode::Pop);
+            }
+        }
+    }
+
+    /// Emits a jump with a placeholder offset and returns where the
+    /// instruction starts, for `patch_jump` to fill in.
     fn emit_jump(&mut self, op_code: OpCode) -> usize {
         self.emit_opcode(op_code);
-        self.state().function.chunk.code.len() - 1
+        self.state().function.chunk.code.len() - JUMP_SIZE
     }
 
     fn patch_jump(&mut self, jmp: usize) {
-        let offset = self.state().function.chunk.code.len() - jmp - 1;
-        match self.state().function.chunk.code.get(jmp) {
-            Some(OpCode::Jump(_)) => {
-                self.state_mut().function.chunk.code[jmp] = OpCode::Jump(offset)
-            }
-            Some(OpCode::JumpIfFalse(_)) => {
-                self.state_mut().function.chunk.code[jmp] = OpCode::JumpIfFalse(offset);
-            }
-            _ => {}
+        let offset = self.state().function.chunk.code.len() - jmp - JUMP_SIZE;
+        if offset > OpCode::Jump(0).max_operand() {
+            self.error("Too much code to jump over.");
+            return;
         }
+        let code = &mut self.state_mut().function.chunk.code;
+        code[jmp + 1..jmp + JUMP_SIZE].copy_from_slice(&(offset as u16).to_be_bytes());
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
-        let line = self.current.as_ref().unwrap().line as u32;
-        let offset = self.state().function.chunk.code.len() - loop_start;
-        self.state_mut()
-            .function
-            .chunk
-            .write_chunk(OpCode::Loop(offset), line);
+        let offset = self.state().function.chunk.code.len() + JUMP_SIZE - loop_start;
+        if offset > OpCode::Loop(0).max_operand() {
+            self.error("Loop body too large.");
+            return;
+        }
+        self.emit_opcode(OpCode::Loop(offset));
     }
 
     fn expression_statement(&mut self, scanner: &mut Scanner) {
@@ -711,11 +1246,7 @@ impl<'a> Compiler<'a> {
             TokenKind::Semicolon,
             "Expect ';' after expression.",
         );
-        let line = self.current.as_ref().unwrap().line as u32;
-        self.state_mut()
-            .function
-            .chunk
-            .write_chunk(OpCode::Pop, line);
+        self.emit_opcode(OpCode::Pop);
     }
 
     fn begin_scope(&mut self) {
@@ -739,7 +1270,13 @@ impl<'a> Compiler<'a> {
         while self.local_count() > 0
             && self.state().locals[self.local_count() - 1].depth > self.scope_depth()
         {
-            self.emit_opcode(OpCode::Pop);
+            // Captured locals get hoisted off the stack instead of discarded
+            let local = self.state_mut().locals.pop().unwrap();
+            if local.is_captured {
+                self.emit_opcode(OpCode::CloseUpvalue);
+            } else {
+                self.emit_opcode(OpCode::Pop);
+            }
             self.state_mut().local_count -= 1;
         }
     }
@@ -753,15 +1290,11 @@ impl<'a> Compiler<'a> {
             let source = compiler
                 .source
                 .get(token.start..(token.start + token.length));
-            match source {
-                Some(code) => {
-                    let value = f32::from_str(code).ok();
-                    if let Some(constant) = value {
-                        let index = compiler.add_constant(Value::Number(constant));
-                        compiler.emit_opcode(OpCode::Constant(index));
-                    }
+            if let Some(code) = source {
+                let value = f64::from_str(code).ok();
+                if let Some(constant) = value {
+                    compiler.emit_constant(Value::Number(constant));
                 }
-                None => (),
             }
         }
     }
@@ -771,19 +1304,9 @@ impl<'a> Compiler<'a> {
             let source = compiler
                 .source
                 .get((token.start + 1)..(token.start + token.length - 1));
-            match source {
-                Some(string) => {
-                    let value = if let Some(existing_string) = compiler.strings.get(string) {
-                        existing_string.to_string()
-                    } else {
-                        let value = String::from(string);
-                        compiler.strings.insert(value.clone());
-                        value
-                    };
-                    let index = compiler.add_constant(Value::Object(Object::String(value)));
-                    compiler.emit_opcode(OpCode::Constant(index));
-                }
-                None => (),
+            if let Some(string) = source {
+                let id = compiler.heap.intern(string);
+                compiler.emit_constant(Value::Object(id));
             }
         }
     }
@@ -799,15 +1322,16 @@ impl<'a> Compiler<'a> {
 
     fn unary(compiler: &mut Compiler, scanner: &mut Scanner, _can_assign: bool) {
         let operator = compiler.previous.as_ref().unwrap().kind.clone();
+        let span = compiler.previous_span();
 
         compiler.parse_precedence(Precedence::Unary, scanner);
 
         match operator {
             TokenKind::Minus => {
-                compiler.emit_opcode(OpCode::Negate);
+                compiler.emit_opcode_at(OpCode::Negate, span);
             }
             TokenKind::Bang => {
-                compiler.emit_opcode(OpCode::Not);
+                compiler.emit_opcode_at(OpCode::Not, span);
             }
             _ => (),
         }
@@ -815,44 +1339,51 @@ impl<'a> Compiler<'a> {
 
     fn binary(compiler: &mut Compiler, scanner: &mut Scanner, _can_assign: bool) {
         let operator = compiler.previous.as_ref().unwrap().kind.clone();
+        let span = compiler.previous_span();
         let rule = compiler.get_rule(&operator);
         compiler.parse_precedence(rule.precedence, scanner);
 
         match operator {
-            TokenKind::Plus => compiler.emit_opcode(OpCode::Add),
-            TokenKind::Minus => compiler.emit_opcode(OpCode::Subtract),
-            TokenKind::Star => compiler.emit_opcode(OpCode::Multiply),
-            TokenKind::Slash => compiler.emit_opcode(OpCode::Divide),
+            TokenKind::Plus => compiler.emit_opcode_at(OpCode::Add, span),
+            TokenKind::Minus => compiler.emit_opcode_at(OpCode::Subtract, span),
+            TokenKind::Star => compiler.emit_opcode_at(OpCode::Multiply, span),
+            TokenKind::Slash => compiler.emit_opcode_at(OpCode::Divide, span),
             TokenKind::BangEqual => {
-                compiler.emit_opcode(OpCode::Equal);
-                compiler.emit_opcode(OpCode::Not);
+                compiler.emit_opcode_at(OpCode::Equal, span);
+                compiler.emit_opcode_at(OpCode::Not, span);
             }
-            TokenKind::EqualEqual => compiler.emit_opcode(OpCode::Equal),
-            TokenKind::Greater => compiler.emit_opcode(OpCode::Greater),
+            TokenKind::EqualEqual => compiler.emit_opcode_at(OpCode::Equal, span),
+            TokenKind::Greater => compiler.emit_opcode_at(OpCode::Greater, span),
             TokenKind::GreaterEqual => {
-                compiler.emit_opcode(OpCode::Less);
-                compiler.emit_opcode(OpCode::Not);
+                compiler.emit_opcode_at(OpCode::Less, span);
+                compiler.emit_opcode_at(OpCode::Not, span);
             }
-            TokenKind::Less => compiler.emit_opcode(OpCode::Less),
+            TokenKind::Less => compiler.emit_opcode_at(OpCode::Less, span),
             TokenKind::LessEqual => {
-                compiler.emit_opcode(OpCode::Greater);
-                compiler.emit_opcode(OpCode::Not);
+                compiler.emit_opcode_at(OpCode::Greater, span);
+                compiler.emit_opcode_at(OpCode::Not, span);
             }
             _ => (),
         }
     }
 
     fn call(compiler: &mut Compiler, scanner: &mut Scanner, _can_assign: bool) {
+        let open_paren = compiler.previous_span();
         let arg_count = compiler.argument_list(scanner);
-        compiler.emit_opcode(OpCode::Call(arg_count));
+        let span = open_paren.to(compiler.previous_span());
+        compiler.emit_opcode_at(OpCode::Call(arg_count), span);
     }
 
     fn argument_list(&mut self, scanner: &mut Scanner) -> usize {
         let mut arg_count = 0;
 
-        if !self.matches(TokenKind::RightParen, scanner) {
+        if !self.check(TokenKind::RightParen) {
             loop {
                 self.expression(scanner);
+                if arg_count == MAX_ARGS {
+                    let message = format!("Can't have more than {} arguments.", MAX_ARGS);
+                    self.error(&message);
+                }
                 arg_count += 1;
                 if !self.matches(TokenKind::Comma, scanner) {
                     break;
@@ -899,40 +1430,150 @@ impl<'a> Compiler<'a> {
     }
 
     fn variable(compiler: &mut Compiler, scanner: &mut Scanner, can_assign: bool) {
-        compiler.named_variable(scanner, can_assign);
+        let name = compiler.lexeme(compiler.previous.as_ref().unwrap());
+        compiler.named_variable(name, scanner, can_assign);
+    }
+
+    fn this(compiler: &mut Compiler, scanner: &mut Scanner, _can_assign: bool) {
+        if compiler.classes.is_empty() {
+            compiler.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        Compiler::variable(compiler, scanner, false);
+    }
+
+    fn super_(compiler: &mut Compiler, scanner: &mut Scanner, _can_assign: bool) {
+        match compiler.classes.last() {
+            None => {
+                compiler.error("Can't use 'super' outside of a class.");
+                return;
+            }
+            Some(class) if !class.has_superclass => {
+                compiler.error("Can't use 'super' in a class with no superclass.");
+                return;
+            }
+            Some(_) => {}
+        }
+
+        compiler.consume(scanner, TokenKind::Dot, "Expect '.' after 'super'.");
+        compiler.consume(scanner, TokenKind::Identifier, "Expect superclass method name.");
+        let name = compiler.lexeme(compiler.previous.as_ref().unwrap());
+        let index = compiler.identifier_constant(name);
+
+        // The receiver, then the superclass the lookup starts from
+        compiler.named_variable("this", scanner, false);
+        compiler.named_variable("super", scanner, false);
+        compiler.emit_opcode(OpCode::GetSuper(index));
     }
 
-    fn named_variable(&mut self, scanner: &mut Scanner, can_assign: bool) {
-        let token = self.previous.clone().unwrap();
+    fn list(compiler: &mut Compiler, scanner: &mut Scanner, _can_assign: bool) {
+        let open_bracket = compiler.previous_span();
+        let mut count = 0;
+        if !compiler.check(TokenKind::RightBracket) {
+            loop {
+                compiler.expression(scanner);
+                if count == MAX_ELEMENTS {
+                    let message = format!("Can't have more than {} elements in a list literal.", MAX_ELEMENTS);
+                    compiler.error(&message);
+                }
+                count += 1;
+                if !compiler.matches(TokenKind::Comma, scanner) {
+                    break;
+                }
+            }
+        }
+        compiler.consume(scanner, TokenKind::RightBracket, "Expect ']' after list elements.");
+        let span = open_bracket.to(compiler.previous_span());
+        compiler.emit_opcode_at(OpCode::BuildList(count), span);
+    }
+
+    /// A `{` where an expression is expected starts a map. Statements never
+    /// get here for one, `statement` takes a leading `{` as a block.
+    fn map(compiler: &mut Compiler, scanner: &mut Scanner, _can_assign: bool) {
+        let open_brace = compiler.previous_span();
+        let mut count = 0;
+        if !compiler.check(TokenKind::RightBrace) {
+            loop {
+                compiler.expression(scanner);
+                compiler.consume(scanner, TokenKind::Colon, "Expect ':' after map key.");
+                compiler.expression(scanner);
+                if count == MAX_ELEMENTS {
+                    let message = format!("Can't have more than {} entries in a map literal.", MAX_ELEMENTS);
+                    compiler.error(&message);
+                }
+                count += 1;
+                if !compiler.matches(TokenKind::Comma, scanner) {
+                    break;
+                }
+            }
+        }
+        compiler.consume(scanner, TokenKind::RightBrace, "Expect '}' after map entries.");
+        let span = open_brace.to(compiler.previous_span());
+        compiler.emit_opcode_at(OpCode::BuildMap(count), span);
+    }
+
+    fn subscript(compiler: &mut Compiler, scanner: &mut Scanner, can_assign: bool) {
+        let open_bracket = compiler.previous_span();
+        compiler.expression(scanner);
+        compiler.consume(scanner, TokenKind::RightBracket, "Expect ']' after index.");
+        let span = open_bracket.to(compiler.previous_span());
+
+        if can_assign && compiler.matches(TokenKind::Equal, scanner) {
+            compiler.expression(scanner);
+            compiler.emit_opcode_at(OpCode::SetIndex, span);
+        } else {
+            compiler.emit_opcode_at(OpCode::GetIndex, span);
+        }
+    }
+
+    fn dot(compiler: &mut Compiler, scanner: &mut Scanner, can_assign: bool) {
+        compiler.consume(scanner, TokenKind::Identifier, "Expect property name after '.'.");
+        let span = compiler.previous_span();
+        let name = compiler.lexeme(compiler.previous.as_ref().unwrap());
+        let index = compiler.identifier_constant(name);
+
+        if can_assign && compiler.matches(TokenKind::Equal, scanner) {
+            compiler.expression(scanner);
+            compiler.emit_opcode_at(OpCode::SetProperty(index), span);
+        } else {
+            compiler.emit_opcode_at(OpCode::GetProperty(index), span);
+        }
+    }
+
+    fn named_variable(&mut self, name: &str, scanner: &mut Scanner, can_assign: bool) {
+        let span = self.previous_span();
         let get_op;
         let set_op;
 
-        if let Some(index) = self.resolve_local(self.current_state_index(), &token) {
+        if let Some(index) = self.resolve_local(self.current_state_index(), name) {
+            if !self.state().locals[index].initialized {
+                self.error("Can't read local variable in its own initializer.");
+            }
             get_op = OpCode::GetLocal(index);
             set_op = OpCode::SetLocal(index);
-        } else if let Some(index) = self.resolve_upvalue(self.current_state_index(), &token) {
+        } else if let Some(index) = self.resolve_upvalue(self.current_state_index(), name) {
             get_op = OpCode::GetUpvalue(index);
             set_op = OpCode::SetUpvalue(index);
         } else {
-            let index = self.identifier_constant(&token);
+            let index = self.identifier_constant(name);
             get_op = OpCode::GetGlobal(index);
             set_op = OpCode::SetGlobal(index);
         }
 
         if can_assign && self.matches(TokenKind::Equal, scanner) {
             self.expression(scanner);
-            self.emit_opcode(set_op);
+            self.emit_opcode_at(set_op, span);
         } else {
-            self.emit_opcode(get_op);
+            self.emit_opcode_at(get_op, span);
         }
     }
 
-    fn resolve_local(&self, state_idx: usize, name: &Token) -> Option<usize> {
+    fn resolve_local(&self, state_idx: usize, name: &str) -> Option<usize> {
         let state = self.states.get(state_idx);
         match state {
             Some(state) => {
                 for (i, local) in state.locals.iter().enumerate().rev() {
-                    if self.identifiers_equal(&local.name, name) {
+                    if local.name == name {
                         return Some(i);
                     }
                 }
@@ -942,46 +1583,38 @@ impl<'a> Compiler<'a> {
         }
     }
 
-    fn resolve_upvalue(&mut self, state_idx: usize, name: &Token) -> Option<usize> {
-        if let Some(state) = self.states.get(state_idx) {
-            if let Some(enclosing_idx) = state.enclosing {
-                if let Some(index) = self.resolve_local(enclosing_idx, name) {
-                    println!("Adding local upvalue for {:?}", &self.source[name.as_range()]);
-                    return self.add_upvalue(index, true);
-                }
+    fn resolve_upvalue(&mut self, state_idx: usize, name: &str) -> Option<usize> {
+        let enclosing_idx = self.states.get(state_idx)?.enclosing?;
 
-                if let Some(index) = self.resolve_upvalue(enclosing_idx, name) {
-                    println!("Adding non-local upvalue for {:?}", &self.source[name.as_range()]);
-                    return self.add_upvalue(index, false);
-                }
-            }
+        if let Some(index) = self.resolve_local(enclosing_idx, name) {
+            self.states[enclosing_idx].locals[index].is_captured = true;
+            return Some(self.add_upvalue(state_idx, index, true));
         }
 
-        None
-    }
-
-    fn add_upvalue(&mut self, index: usize, local: bool) -> Option<usize> {
-        let mut upvalue_count = self.state().function.upvalue_count;
-        for i in 0..upvalue_count {
-            let upvalue = &self.upvalues[i];
-            if upvalue.local == local && upvalue.index == index {
-                return Some(i);
-            }
+        if let Some(index) = self.resolve_upvalue(enclosing_idx, name) {
+            return Some(self.add_upvalue(state_idx, index, false));
         }
-        self.upvalues.push(Upvalue::new(local, index));
-        upvalue_count += 1;
-        self.state_mut().function.upvalue_count = upvalue_count;
-        Some(upvalue_count)
+
+        None
     }
 
-    fn identifiers_equal(&self, lhs: &Token, rhs: &Token) -> bool {
-        if lhs.length != rhs.length {
-            return false;
+    fn add_upvalue(&mut self, state_idx: usize, index: usize, local: bool) -> usize {
+        let state = &mut self.states[state_idx];
+        let existing = state
+            .upvalues
+            .iter()
+            .position(|upvalue| upvalue.local == local && upvalue.index == index);
+        if let Some(existing) = existing {
+            return existing;
         }
 
-        if self.source[lhs.as_range()] == self.source[rhs.as_range()] {
-            return true;
+        if state.upvalues.len() == MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return 0;
         }
-        false
+        let state = &mut self.states[state_idx];
+        state.upvalues.push(Upvalue::new(local, index));
+        state.function.upvalue_count = state.upvalues.len();
+        state.upvalues.len() - 1
     }
 }
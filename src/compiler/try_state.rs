@@ -0,0 +1,35 @@
+/// How the code protected by a `try` finished when it didn't simply run off
+/// the end. `finally` runs first, then the exit carries on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    Return,
+    Break,
+    Continue,
+}
+
+/// Tracks a `try` being compiled. Two hidden locals record how its body or
+/// `catch` finished, so `finally` can run before anything leaves.
+#[derive(Debug, Clone)]
+pub struct TryState {
+    /// The depth of the hidden locals; anything deeper is discarded on the
+    /// way to `finally`.
+    pub scope_depth: usize,
+    /// The slot holding a completion code: normal, thrown or an exit.
+    pub completion: usize,
+    /// The slot holding what was thrown or returned.
+    pub value: usize,
+    /// Each exit taken and its jump to `finally`, in the order of their
+    /// completion codes.
+    pub exits: Vec<(Exit, usize)>,
+}
+
+impl TryState {
+    pub fn new(scope_depth: usize, completion: usize, value: usize) -> Self {
+        Self {
+            scope_depth,
+            completion,
+            value,
+            exits: Vec::new(),
+        }
+    }
+}
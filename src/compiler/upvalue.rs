@@ -3,3 +3,9 @@ pub struct Upvalue {
     pub local: bool,
     pub index: usize,
 }
+
+impl Upvalue {
+    pub fn new(local: bool, index: usize) -> Self {
+        Self { local, index }
+    }
+}
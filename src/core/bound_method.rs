@@ -0,0 +1,13 @@
+use crate::vm::ObjectId;
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct BoundMethod {
+    pub receiver: ObjectId,
+    pub method: ObjectId,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: ObjectId, method: ObjectId) -> Self {
+        BoundMethod { receiver, method }
+    }
+}
@@ -0,0 +1,40 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::vm::ObjectId;
+
+#[derive(Debug, Clone)]
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<String, ObjectId>,
+    pub superclass: Option<ObjectId>,
+}
+
+impl Class {
+    pub fn new(name: &str) -> Self {
+        Class {
+            name: String::from(name),
+            methods: HashMap::new(),
+            superclass: None,
+        }
+    }
+}
+
+impl PartialEq for Class {
+    fn eq(&self, other: &Class) -> bool {
+        self.name == other.name
+    }
+}
+
+impl PartialOrd for Class {
+    fn partial_cmp(&self, other: &Class) -> Option<Ordering> {
+        self.name.partial_cmp(&other.name)
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
@@ -1,24 +1,16 @@
-use crate::core::Function;
-use crate::core::UpvalueRef;
-use std::fmt;
+use crate::vm::ObjectId;
 
+/// A function paired with the variables it captured. Both the function and
+/// the upvalues live on the heap.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub struct Closure {
-    pub function: Function,
-    pub upvalues: Vec<UpvalueRef>,
-    pub upvalue_count: usize
+    pub function: ObjectId,
+    pub upvalues: Vec<ObjectId>,
 }
 
 impl Closure {
-    pub fn new(function: Function) -> Self {
-        let upvalues: Vec<UpvalueRef> = Vec::new();
-        let upvalue_count = function.upvalue_count;
-        Closure { function, upvalues, upvalue_count }
-    }
-}
-
-impl fmt::Display for Closure {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.function)
+    pub fn new(function: ObjectId) -> Self {
+        let upvalues: Vec<ObjectId> = Vec::new();
+        Closure { function, upvalues }
     }
 }
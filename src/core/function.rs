@@ -1,7 +1,9 @@
 use std::cmp::Ordering;
 use std::fmt;
+use std::io;
 
-use crate::vm::Chunk;
+use crate::core::Value;
+use crate::vm::{Chunk, Heap};
 
 #[derive(Debug, Clone)]
 pub struct Function {
@@ -16,6 +18,8 @@ pub struct Function {
 pub enum FunctionType {
     Script,
     Function,
+    Method,
+    Initializer,
     Native,
 }
 
@@ -30,18 +34,41 @@ impl Function {
         }
     }
 
-    pub fn disassemble(&self) {
-        self.chunk.disassemble(&self.name)
+    /// Lists this function's bytecode followed by that of every function
+    /// declared inside it, depth first.
+    pub fn disassemble(&self, heap: &Heap) -> String {
+        let mut out = Vec::new();
+        self.write_disassembly(heap, &mut out).expect("Writing to a Vec can't fail");
+        String::from_utf8(out).expect("Listings are UTF-8")
+    }
+
+    /// Writes the same listing as `disassemble` a line at a time, so a big
+    /// script is never held in memory whole.
+    pub fn write_disassembly<W: io::Write>(&self, heap: &Heap, out: &mut W) -> io::Result<()> {
+        self.chunk.write_disassembly(&self.name, heap, out)?;
+        for constant in &self.chunk.constants {
+            if let Value::Object(id) = constant {
+                if let Some(function) = heap.get_function(id) {
+                    writeln!(out)?;
+                    function.write_disassembly(heap, out)?;
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn name(&self) -> &String {
         &self.name
     }
+
+    pub fn function_type(&self) -> &FunctionType {
+        &self.function_type
+    }
 }
 
 impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<fn {} airty: {}>", self.name, self.arity)
+        write!(f, "<fn {} arity: {}>", self.name, self.arity)
     }
 }
 
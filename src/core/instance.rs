@@ -0,0 +1,39 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::core::Value;
+use crate::vm::ObjectId;
+
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub class: ObjectId,
+    pub fields: HashMap<String, Value>,
+}
+
+impl Instance {
+    pub fn new(class: ObjectId) -> Self {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Instance) -> bool {
+        self.class == other.class && self.fields == other.fields
+    }
+}
+
+impl PartialOrd for Instance {
+    fn partial_cmp(&self, _other: &Instance) -> Option<Ordering> {
+        None
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "instance")
+    }
+}
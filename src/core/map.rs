@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use crate::core::{Object, RuntimeError, Value};
+use crate::vm::{Heap, ObjectId};
+
+/// The hashable form of a value used as a map key. Strings are interned, so
+/// their id stands in for their contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    String(ObjectId),
+}
+
+impl MapKey {
+    /// Only strings, numbers, booleans and nil can be keys. Other objects
+    /// are compared by identity everywhere else, which would make a key
+    /// impossible to look up with a fresh copy.
+    pub fn new(value: &Value, heap: &Heap) -> Result<MapKey, RuntimeError> {
+        match value {
+            Value::Nil => Ok(MapKey::Nil),
+            Value::Bool(b) => Ok(MapKey::Bool(*b)),
+            Value::Number(n) if n.is_nan() => Err(RuntimeError::new("NaN can't be a map key.")),
+            // 0 and -0 are equal, so they must hash the same
+            Value::Number(n) if *n == 0.0 => Ok(MapKey::Number(0f64.to_bits())),
+            Value::Number(n) => Ok(MapKey::Number(n.to_bits())),
+            Value::Object(id) => match heap.get(id) {
+                Some(Object::String(_)) => Ok(MapKey::String(*id)),
+                _ => Err(RuntimeError::new(
+                    "Map keys must be strings, numbers, booleans or nil.",
+                )),
+            },
+        }
+    }
+}
+
+/// A Lox map. Entries keep the order they were first inserted in, so
+/// printing and `keys` give the same answer every run.
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
+}
+
+impl Map {
+    pub(crate) fn new() -> Self {
+        Map::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn get(&self, key: &MapKey) -> Option<Value> {
+        self.index.get(key).map(|i| self.entries[*i].1)
+    }
+
+    pub(crate) fn contains(&self, key: &MapKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Sets the value for `key`, which was made from `key_value`. A key
+    /// that's already present keeps its place.
+    pub(crate) fn insert(&mut self, key: MapKey, key_value: Value, value: Value) {
+        match self.index.get(&key) {
+            Some(i) => self.entries[*i].1 = value,
+            None => {
+                self.index.insert(key, self.entries.len());
+                self.entries.push((key_value, value));
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let removed = self.index.remove(key)?;
+        for i in self.index.values_mut() {
+            if *i > removed {
+                *i -= 1;
+            }
+        }
+        Some(self.entries.remove(removed).1)
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+}
+
+impl PartialEq for Map {
+    fn eq(&self, other: &Map) -> bool {
+        self.entries == other.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_insertion_order_through_removals() {
+        let mut map = Map::new();
+        for n in 0..4 {
+            let n = f64::from(n);
+            map.insert(MapKey::Number(n.to_bits()), Value::Number(n), Value::Nil);
+        }
+        map.insert(MapKey::Number(0f64.to_bits()), Value::Number(0.0), Value::Bool(true));
+        assert_eq!(map.remove(&MapKey::Number(1f64.to_bits())), Some(Value::Nil));
+        assert_eq!(map.remove(&MapKey::Nil), None);
+
+        let keys: Vec<Value> = map.entries().iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, vec![Value::Number(0.0), Value::Number(2.0), Value::Number(3.0)]);
+        assert_eq!(map.get(&MapKey::Number(3f64.to_bits())), Some(Value::Nil));
+        assert_eq!(map.get(&MapKey::Number(0f64.to_bits())), Some(Value::Bool(true)));
+    }
+}
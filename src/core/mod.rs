@@ -1,13 +1,25 @@
+mod bound_method;
+mod class;
 mod closure;
 mod function;
+mod instance;
+mod map;
 mod native_function;
+mod runtime_error;
+mod span;
 mod value;
 mod upvalue_ref;
 mod object;
 
 pub use value::{Value};
 pub use upvalue_ref::UpvalueRef;
+pub use bound_method::BoundMethod;
+pub use class::Class;
 pub use closure::Closure;
 pub use function::{Function, FunctionType};
-pub use native_function::NativeFunction;
-pub use object::Object;
\ No newline at end of file
+pub use instance::Instance;
+pub use map::{Map, MapKey};
+pub use native_function::{Arity, NativeFn, NativeFunction};
+pub use runtime_error::{RuntimeError, TraceFrame};
+pub use span::Span;
+pub use object::Object;
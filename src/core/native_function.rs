@@ -1,11 +1,59 @@
-use crate::core::Value;
+use crate::core::{RuntimeError, Value};
+use crate::vm::Heap;
 use std::cmp::Ordering;
 use std::fmt;
 
+/// The Rust side of a native. It receives the call's arguments, in order,
+/// and the heap so it can read strings, lists and maps or allocate new ones.
+pub type NativeFn = fn(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError>;
+
+/// How many arguments a native accepts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Fixed(usize),
+    /// Any number of arguments, as long as there are at least this many.
+    Variadic(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, arg_count: usize) -> bool {
+        match self {
+            Arity::Fixed(arity) => arg_count == *arity,
+            Arity::Variadic(min) => arg_count >= *min,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NativeFunction {
     pub name: String,
-    pub function: fn() -> Value,
+    pub arity: Arity,
+    pub function: NativeFn,
+}
+
+impl NativeFunction {
+    pub fn new(name: &str, arity: Arity, function: NativeFn) -> Self {
+        NativeFunction {
+            name: String::from(name),
+            arity,
+            function,
+        }
+    }
+
+    pub fn call(&self, heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+        if !self.arity.accepts(args.len()) {
+            let expected = match self.arity {
+                Arity::Fixed(arity) => format!("{}", arity),
+                Arity::Variadic(min) => format!("at least {}", min),
+            };
+            return Err(RuntimeError::new(&format!(
+                "Expected {} arguments but got {}.",
+                expected,
+                args.len()
+            )));
+        }
+        (self.function)(heap, args)
+    }
 }
 
 impl PartialEq for NativeFunction {
@@ -1,30 +1,23 @@
-use std::fmt;
+use crate::core::{BoundMethod, Class, Closure, Function, Instance, NativeFunction, Map, UpvalueRef, Value};
 
-use crate::core::{Closure, UpvalueRef, NativeFunction};
-
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+/// Everything that lives on the heap. Values refer to these by `ObjectId`;
+/// see `Heap::format_value` for how each one prints.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Object {
     String(String),
+    Function(Function),
     NativeFunction(NativeFunction),
     Closure(Closure),
-    Upvalue(UpvalueRef)
-}
-
-impl fmt::Display for Object {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Object::String(value) => write!(f, "{}", value),
-            Object::NativeFunction(value) => write!(f, "{}", value),
-            Object::Closure(object) => {
-                write!(f, "{:?}", *object)
-            },
-            Object::Upvalue(value) => write!(f, "{}", value),
-        }
-    }
+    Upvalue(UpvalueRef),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    List(Vec<Value>),
+    Map(Map),
 }
 
 impl From<String> for Object {
     fn from(s: String) -> Object {
         Object::String(s)
     }
-}
\ No newline at end of file
+}
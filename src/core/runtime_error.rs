@@ -0,0 +1,60 @@
+use std::fmt;
+
+use crate::core::Span;
+
+/// An error raised while executing bytecode, either by the VM itself or by a
+/// native function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The call stack when the error was raised, innermost call first.
+    pub trace: Vec<TraceFrame>,
+}
+
+/// One active call in a runtime error's stack trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// The function's name, or `None` for the top level script.
+    pub function: Option<String>,
+    pub line: u32,
+    /// The source the failing instruction was compiled from.
+    pub span: Span,
+}
+
+impl RuntimeError {
+    pub fn new(message: &str) -> Self {
+        RuntimeError::with_trace(message, Vec::new())
+    }
+
+    pub fn with_trace(message: &str, trace: Vec<TraceFrame>) -> Self {
+        RuntimeError {
+            message: String::from(message),
+            trace,
+        }
+    }
+}
+
+impl TraceFrame {
+    pub fn new(function: Option<String>, line: u32, span: Span) -> Self {
+        TraceFrame {
+            function,
+            line,
+            span,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use std::ops::Range;
+
+/// A byte range of the source, used to point diagnostics at the exact code
+/// an instruction was compiled from.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, length: usize) -> Self {
+        Span { start, length }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        let start = self.start.min(other.start);
+        let end = (self.start + self.length).max(other.start + other.length);
+        Span::new(start, end - start)
+    }
+
+    pub fn as_range(&self) -> Range<usize> {
+        self.start..(self.start + self.length)
+    }
+}
@@ -1,27 +1,34 @@
 use std::fmt;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+use crate::core::Value;
+
+/// A captured variable. While open it points at a live stack slot; once the
+/// slot goes out of scope the value is hoisted into `closed`.
+#[derive(Debug, Clone, PartialEq)]
 pub struct UpvalueRef {
-    local: bool,
-    location: usize
+    pub location: usize,
+    pub closed: Option<Value>,
 }
 
 impl UpvalueRef {
-    pub fn new(local: bool, location: usize) -> Self {
-        Self { local, location }
+    pub fn new(location: usize) -> Self {
+        Self {
+            location,
+            closed: None,
+        }
     }
 
-    pub fn local(&self) -> bool {
-        self.local
+    pub fn is_open(&self) -> bool {
+        self.closed.is_none()
+    }
+
+    pub fn close(&mut self, value: Value) {
+        self.closed = Some(value);
     }
 }
 
 impl fmt::Display for UpvalueRef {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.local {
-          write!(f, "Local value")
-        } else {
-          write!(f, "Upvalue")
-        }
+        write!(f, "upvalue")
     }
 }
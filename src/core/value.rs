@@ -1,32 +1,32 @@
+use std::convert::TryFrom;
 use std::fmt;
 use std::ops::{Add, Div, Mul, Neg, Sub};
 
-use crate::core::Object;
+use crate::core::RuntimeError;
 use crate::vm::ObjectId;
 
-#[derive(Clone, Debug, PartialEq, PartialOrd)]
+/// A Lox value. Anything larger than a number lives on the `Heap` and is
+/// referred to by handle, so values are cheap to copy around the stack.
+/// There's deliberately no ordering: handles say nothing about contents.
+///
+/// For the same reason `Display` only shows an object's handle. Use
+/// `Vm::format_value`, or `Heap::format_value` inside a native, to print
+/// objects the way Lox does.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Value {
     Nil,
     Bool(bool),
-    Number(f32),
-    Object(Object),
-    HeapObject(ObjectId)
+    Number(f64),
+    Object(ObjectId),
 }
 
 impl Value {
     pub fn is_number(&self) -> bool {
-        match self {
-            Value::Number(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Number(_))
     }
 
     pub fn is_falsey(&self) -> bool {
-        match self {
-            Value::Nil => true,
-            Value::Bool(false) => true,
-            _ => false,
-        }
+        matches!(self, Value::Nil | Value::Bool(false))
     }
 }
 
@@ -35,17 +35,86 @@ impl fmt::Display for Value {
         match self {
             Value::Nil => write!(f, "nil"),
             Value::Bool(value) => write!(f, "{}", value),
-            Value::Number(value) => write!(f, "{}", value),
-            Value::Object(object) => {
-                write!(f, "{}", *object)
-            },
-            Value::HeapObject(id) => {
-                write!(f, "{:?}", *id)
-            }
+            Value::Number(value) => write!(f, "{}", format_number(*value)),
+            Value::Object(id) => write!(f, "{:?}", id),
         }
     }
 }
 
+impl From<()> for Value {
+    fn from(_: ()) -> Value {
+        Value::Nil
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+/// Integers print without a trailing `.0`, as in the other Lox
+/// implementations, and the special values the way C's `printf` spells them.
+fn format_number(value: f64) -> String {
+    if value.is_nan() {
+        String::from("nan")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "inf" } else { "-inf" })
+    } else if value == 0.0 && value.is_sign_negative() {
+        String::from("-0")
+    } else {
+        value.to_string()
+    }
+}
+
+impl From<f32> for Value {
+    fn from(value: f32) -> Value {
+        Value::Number(f64::from(value))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Number(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Value {
+        Value::Number(f64::from(value))
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> Result<bool, RuntimeError> {
+        match value {
+            Value::Bool(value) => Ok(value),
+            _ => Err(RuntimeError::new("Expected a boolean.")),
+        }
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> Result<f64, RuntimeError> {
+        match value {
+            Value::Number(value) => Ok(value),
+            _ => Err(RuntimeError::new("Expected a number.")),
+        }
+    }
+}
+
+impl TryFrom<Value> for f32 {
+    type Error = RuntimeError;
+
+    fn try_from(value: Value) -> Result<f32, RuntimeError> {
+        f64::try_from(value).map(|value| value as f32)
+    }
+}
+
 impl Add for Value {
     type Output = Result<Self, &'static str>;
 
@@ -112,3 +181,19 @@ impl Neg for Value {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_numbers_like_lox() {
+        assert_eq!(Value::Number(3.0).to_string(), "3");
+        assert_eq!(Value::Number(-2.5).to_string(), "-2.5");
+        assert_eq!(Value::Number(0.1 + 0.2).to_string(), "0.30000000000000004");
+        assert_eq!(Value::Number(9007199254740993.0).to_string(), "9007199254740992");
+        assert_eq!(Value::Number(-0.0).to_string(), "-0");
+        assert_eq!(Value::Number(f64::INFINITY).to_string(), "inf");
+        assert_eq!(Value::Number(f64::NAN).to_string(), "nan");
+    }
+}
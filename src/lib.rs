@@ -2,27 +2,51 @@ use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::path::{Path, PathBuf};
 
-use colored::*;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 mod core;
 mod scanner;
 mod compiler;
+mod report;
 mod vm;
 
-use vm::{Vm, VmResult};
+pub use crate::core::{Arity, Map, NativeFn, RuntimeError, Span, TraceFrame, Value};
+pub use compiler::{CompilerError, Diagnostic, Label, Location};
+pub use report::{ErrorFormat, Report};
+pub use vm::{is_bytecode, Heap, LoadError, ObjectId, TraceKind, TraceOptions, Tracer, Vm, VmResult, WriteTracer};
 
-pub fn repl() -> io::Result<()> {
-    let mut rl = Editor::<()>::new();
+/// How the command line runs scripts.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub error_format: ErrorFormat,
+    pub trace: TraceOptions,
+    /// Where trace output goes, or stderr when `None`.
+    pub trace_file: Option<String>,
+}
+
+pub fn repl(options: &Options) -> io::Result<()> {
+    let mut rl = Editor::<()>::new().map_err(io::Error::other)?;
     rl.load_history("~/.lox_history").ok();
+    // One VM for the whole session so earlier lines stay defined
+    let mut vm = new_vm(options)?;
     loop {
         let readline = rl.readline("lox > ");
         match readline {
             Ok(line) => {
                 rl.add_history_entry(&line);
-                interpret(&line);
+                let report = Report::new("<repl>", &line);
+                match vm.interpret(&line) {
+                    VmResult::CompileError(error) => {
+                        report_compile_error(&report, &error, ErrorFormat::Human)
+                    }
+                    // The failing function may come from an earlier line, so
+                    // there is no snippet to show
+                    VmResult::RuntimeError(error) => eprint!("{}", report.runtime_error_trace(&error)),
+                    _ => (),
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("Exiting...");
@@ -39,43 +63,248 @@ pub fn repl() -> io::Result<()> {
     Ok(())
 }
 
-pub fn run_file(path: &str) -> io::Result<()> {
-    let file = File::open(path)?;
-    let mut buf_reader = BufReader::new(file);
-    let mut contents = String::new();
-    buf_reader.read_to_string(&mut contents)?;
-    match interpret(&contents) {
-        VmResult::CompileError => std::process::exit(65),
-        VmResult::SyntaxError => std::process::exit(65),
-        VmResult::RuntimeError(error_message) => {
-            let message = format!("Lox::RuntimeError: {}", error_message);
-            eprintln!("{}", message.red());
-            std::process::exit(70)
+/// Exit code for a script that failed to compile.
+pub const EXIT_COMPILE_ERROR: i32 = 65;
+/// Exit code for a script that raised a runtime error.
+pub const EXIT_RUNTIME_ERROR: i32 = 70;
+
+/// Reads a script from `path`, or from standard input when `path` is `-`.
+pub fn read_source(path: &str) -> io::Result<String> {
+    let bytes = read_bytes(path)?;
+    read_source_bytes(path, bytes)
+}
+
+fn read_bytes(path: &str) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let read = if path == "-" {
+        io::stdin().read_to_end(&mut bytes)
+    } else {
+        File::open(path).and_then(|file| BufReader::new(file).read_to_end(&mut bytes))
+    };
+    match read {
+        Ok(_) => Ok(bytes),
+        Err(error) => Err(io::Error::new(
+            error.kind(),
+            format!("Could not read '{}': {}", path, error),
+        )),
+    }
+}
+
+/// Runs the script or `.loxc` file at `path` with `args` and returns the
+/// exit code.
+pub fn run_file(path: &str, args: &[String], options: &Options) -> io::Result<i32> {
+    let bytes = read_bytes(path)?;
+    if !is_bytecode(&bytes) {
+        let source = read_source_bytes(path, bytes)?;
+        return run_source(source_name(path), &source, args, options);
+    }
+
+    let report = Report::without_source(source_name(path));
+    let mut vm = new_vm(options)?;
+    vm.set_args(args);
+    Ok(report_result(&report, vm.interpret_bytecode(&bytes), options.error_format))
+}
+
+/// Runs `source` with `args` and returns the exit code. `name` is what
+/// errors call the source.
+pub fn run_source(name: &str, source: &str, args: &[String], options: &Options) -> io::Result<i32> {
+    let report = Report::new(name, source);
+    let mut vm = new_vm(options)?;
+    vm.set_args(args);
+    Ok(report_result(&report, vm.interpret(source), options.error_format))
+}
+
+/// Compiles the script at `path` to a `.loxc` file at `output`, next to the
+/// script when `output` is `None`, and returns the exit code.
+pub fn compile_file(path: &str, output: Option<&str>, options: &Options) -> io::Result<i32> {
+    let source = read_source(path)?;
+    let report = Report::new(source_name(path), &source);
+    let bytes = match Vm::new().compile_bytecode(&source) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            report_compile_error(&report, &error, options.error_format);
+            return Ok(EXIT_COMPILE_ERROR);
         }
-        VmResult::Ok => std::process::exit(0),
+    };
+
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None if path == "-" => PathBuf::from("out.loxc"),
+        None => Path::new(path).with_extension("loxc"),
+    };
+    std::fs::write(&output, bytes).map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!("Could not write '{}': {}", output.display(), error),
+        )
+    })?;
+    Ok(0)
+}
+
+/// Compiles the script at `path` without running it and returns the exit
+/// code. A `.loxc` file is checked by loading it.
+pub fn check_file(path: &str, options: &Options) -> io::Result<i32> {
+    let bytes = read_bytes(path)?;
+    if is_bytecode(&bytes) {
+        let report = Report::without_source(source_name(path));
+        return Ok(match Vm::new().disassemble_bytecode(&bytes) {
+            Ok(_) => 0,
+            Err(error) => report_load_error(&report, &error, options.error_format),
+        });
+    }
+
+    let source = read_source_bytes(path, bytes)?;
+    let report = Report::new(source_name(path), &source);
+    match Vm::new().check(&source) {
+        Ok(()) => Ok(0),
+        Err(error) => {
+            report_compile_error(&report, &error, options.error_format);
+            Ok(EXIT_COMPILE_ERROR)
+        }
+    }
+}
+
+/// Prints the bytecode of every function in the script or `.loxc` file at
+/// `path` and returns the exit code.
+pub fn disassemble_file(path: &str, options: &Options) -> io::Result<i32> {
+    let bytes = read_bytes(path)?;
+    if is_bytecode(&bytes) {
+        let report = Report::without_source(source_name(path));
+        return match Vm::new().disassemble_bytecode(&bytes) {
+            Ok(listing) => io::stdout().write_all(listing.as_bytes()).map(|_| 0),
+            Err(error) => Ok(report_load_error(&report, &error, options.error_format)),
+        };
     }
+
+    let source = read_source_bytes(path, bytes)?;
+    let report = Report::new(source_name(path), &source);
+    match Vm::new().disassemble(&source) {
+        Ok(listing) => {
+            io::stdout().write_all(listing.as_bytes())?;
+            Ok(0)
+        }
+        Err(error) => {
+            report_compile_error(&report, &error, options.error_format);
+            Ok(EXIT_COMPILE_ERROR)
+        }
+    }
+}
+
+fn read_source_bytes(path: &str, bytes: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Could not read '{}': not valid UTF-8", path),
+        )
+    })
 }
 
-fn interpret(source: &str) -> VmResult {
+/// A VM that traces the way `options` asks.
+fn new_vm(options: &Options) -> io::Result<Vm> {
     let mut vm = Vm::new();
-    vm.interpret(source)
+    if options.trace.any() {
+        let out: Box<dyn Write> = match &options.trace_file {
+            Some(path) => match File::create(path) {
+                Ok(file) => Box::new(io::BufWriter::new(file)),
+                Err(error) => {
+                    return Err(io::Error::new(
+                        error.kind(),
+                        format!("Could not create trace file '{}': {}", path, error),
+                    ))
+                }
+            },
+            None => Box::new(io::stderr()),
+        };
+        vm.set_tracer(Box::new(WriteTracer::new(out, options.trace.clone())));
+    }
+    Ok(vm)
+}
+
+fn source_name(path: &str) -> &str {
+    if path == "-" {
+        "<stdin>"
+    } else {
+        path
+    }
+}
+
+/// Reports how running a script went and returns the exit code.
+fn report_result(report: &Report, result: VmResult, format: ErrorFormat) -> i32 {
+    match result {
+        VmResult::Ok => 0,
+        VmResult::CompileError(error) => {
+            report_compile_error(report, &error, format);
+            EXIT_COMPILE_ERROR
+        }
+        VmResult::SyntaxError => EXIT_COMPILE_ERROR,
+        VmResult::LoadError(error) => report_load_error(report, &error, format),
+        VmResult::RuntimeError(error) => {
+            match format {
+                ErrorFormat::Human => eprint!("{}", report.runtime_error(&error)),
+                ErrorFormat::Json => eprint!("{}", report.runtime_error_json(&error)),
+            }
+            EXIT_RUNTIME_ERROR
+        }
+    }
+}
+
+fn report_compile_error(report: &Report, error: &CompilerError, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Human => eprint!("{}", report.compile_error(error)),
+        ErrorFormat::Json => eprint!("{}", report.compile_error_json(error)),
+    }
+}
+
+fn report_load_error(report: &Report, error: &LoadError, format: ErrorFormat) -> i32 {
+    let message = error.to_string();
+    match format {
+        ErrorFormat::Human => eprint!("{}", report.file_error(&message)),
+        ErrorFormat::Json => eprint!("{}", report.file_error_json("load", &message)),
+    }
+    EXIT_COMPILE_ERROR
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::convert::TryFrom;
+
+    fn interpret(source: &str) -> VmResult {
+        let mut vm = Vm::new();
+        vm.interpret(source)
+    }
+
+    fn error_message(result: VmResult) -> Option<String> {
+        match result {
+            VmResult::RuntimeError(error) => Some(error.message),
+            _ => None,
+        }
+    }
 
     fn test_file(path: &str) -> String {
-        let file = File::open(path).ok().expect("Couldn't find test file");
+        let file = File::open(path).expect("Couldn't find test file");
         let mut buf_reader = BufReader::new(file);
         let mut source = String::new();
         buf_reader
             .read_to_string(&mut source)
-            .ok()
             .expect("Couldn't read test file");
         source
     }
 
+    /// Runs a fixture and formats the `result` global it leaves behind.
+    fn fixture_result(path: &str) -> String {
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&test_file(path)), VmResult::Ok, "{}", path);
+        let result = vm.get_global("result").expect("Fixture should set result");
+        vm.format_value(&result)
+    }
+
+    /// The first diagnostic reported for a fixture that doesn't compile.
+    fn compile_error(path: &str) -> String {
+        let error = Vm::new().check(&test_file(path)).unwrap_err();
+        error.diagnostics[0].message.clone()
+    }
+
     #[test]
     fn comments() {
         let source = test_file("test/test-1.lox");
@@ -122,10 +351,7 @@ mod tests {
     fn unlike_types_additon_error() {
         let source = test_file("test/test-6-error.lox");
         let result = interpret(&source);
-        assert_eq!(
-            result,
-            VmResult::RuntimeError("Operand must be a number.".to_string())
-        );
+        assert_eq!(error_message(result), Some("Operands must be two numbers or two strings.".to_string()));
     }
 
     #[test]
@@ -138,8 +364,11 @@ mod tests {
     #[test]
     fn operation_ordering_error() {
         let source = test_file("test/test-8.lox");
-        let result = interpret(&source);
-        assert_eq!(result, VmResult::Ok);
+        let result = match interpret(&source) {
+            VmResult::CompileError(error) => error.diagnostics[0].message.clone(),
+            result => panic!("Expected a compile error, got {:?}", result),
+        };
+        assert_eq!(result, "Invalid assignment target.");
     }
 
     #[test]
@@ -237,17 +466,795 @@ mod tests {
     fn stack_trace() {
         let source = test_file("test/test-22.lox");
         let result = interpret(&source);
-        assert_eq!(
-            result,
-            VmResult::RuntimeError("An error occurred calling a function.".to_string())
-        );
+        assert_eq!(error_message(result), Some("Expected 0 arguments but got 2.".to_string()));
+    }
+
+    #[test]
+    fn comparing_strings_error() {
+        let source = test_file("test/test-46-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Operands must be numbers.".to_string()));
+    }
+
+    #[test]
+    fn comparing_nil_error() {
+        let source = test_file("test/test-47-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Operands must be numbers.".to_string()));
+    }
+
+    #[test]
+    fn comparing_booleans_error() {
+        let source = test_file("test/test-48-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Operands must be numbers.".to_string()));
+    }
+
+    #[test]
+    fn too_few_arguments_to_a_function() {
+        let source = test_file("test/test-44-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Expected 2 arguments but got 1.".to_string()));
+    }
+
+    #[test]
+    fn too_few_arguments_to_an_initializer() {
+        let source = test_file("test/test-45-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Expected 1 arguments but got 0.".to_string()));
+    }
+
+    #[test]
+    fn class_fields() {
+        let source = test_file("test/test-29.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn class_methods() {
+        let source = test_file("test/test-30.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn class_initializers() {
+        let source = test_file("test/test-31.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn undefined_property_error() {
+        let source = test_file("test/test-32-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Undefined property 'missing'.".to_string()));
+    }
+
+    #[test]
+    fn superclass_methods() {
+        let source = test_file("test/test-33.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn superclass_initializers() {
+        let source = test_file("test/test-34.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn super_survives_reassigned_class_names() {
+        assert_eq!(fixture_result("test/test-51.lox"), "woof ...");
+    }
+
+    #[test]
+    fn super_survives_a_shadowed_class_name() {
+        assert_eq!(fixture_result("test/test-52.lox"), "woof ...");
+    }
+
+    #[test]
+    fn inherit_from_non_class_error() {
+        let source = test_file("test/test-35-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Superclass must be a class.".to_string()));
     }
 
     #[test]
-    #[ignore]
     fn closures() {
         let source = test_file("test/test-28.lox");
         let result = interpret(&source);
         assert_eq!(result, VmResult::Ok);
     }
+
+    #[test]
+    fn reading_upvalues() {
+        let source = test_file("test/test-26.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn assigning_upvalues() {
+        let source = test_file("test/test-27.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn closed_upvalues() {
+        let source = test_file("test/test-36.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn garbage_collection() {
+        let source = test_file("test/test-37.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn native_functions() {
+        let source = test_file("test/test-38.lox");
+        let result = interpret(&source);
+        assert_eq!(result, VmResult::Ok);
+    }
+
+    #[test]
+    fn native_argument_error() {
+        let source = test_file("test/test-39-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Argument must be a number.".to_string()));
+    }
+
+    #[test]
+    fn native_arity_error() {
+        let source = test_file("test/test-40-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Expected 1 arguments but got 2.".to_string()));
+    }
+
+    #[test]
+    fn variadic_native_arity_error() {
+        let source = test_file("test/test-41-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Expected at least 1 arguments but got 0.".to_string()));
+    }
+
+    #[test]
+    fn call_lox_function_from_rust() {
+        let mut vm = Vm::new();
+        let source = "fun add(a, b) { return a + b; } class Point {}";
+        assert_eq!(vm.interpret(source), VmResult::Ok);
+
+        let result = vm.call_function("add", &[Value::from(1.5), Value::from(2)]);
+        assert_eq!(result.and_then(f64::try_from), Ok(3.5));
+
+        let point = vm.call_function("Point", &[]).unwrap();
+        assert_eq!(vm.format_value(&point), "Point instance");
+
+        let missing = vm.call_function("missing", &[]);
+        assert_eq!(missing, Err(RuntimeError::new("Undefined variable 'missing'.")));
+    }
+
+    #[test]
+    fn register_host_natives_and_globals() {
+        fn greet(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+            let name = heap.format_value(&args[0]);
+            Ok(Value::Object(heap.intern(&format!("Hello, {}!", name))))
+        }
+
+        let mut vm = Vm::new();
+        vm.define_native("greet", Arity::Fixed(1), greet);
+        let name = vm.new_string("world");
+        vm.define_global("name", name);
+        vm.define_global("answer", Value::from(42));
+
+        let source = "var greeting = greet(name); var twice = answer * 2;";
+        assert_eq!(vm.interpret(source), VmResult::Ok);
+
+        let greeting = vm.get_global("greeting").unwrap();
+        assert_eq!(vm.get_string(&greeting), Some("Hello, world!"));
+        assert_eq!(vm.get_global("twice").map(f64::try_from), Some(Ok(84.0)));
+        assert!(bool::try_from(Value::Nil).is_err());
+    }
+
+    #[test]
+    fn host_natives_read_and_build_collections() {
+        // Counts each string in a list, answering with a map and its keys
+        fn tally(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+            let items = match args[0] {
+                Value::Object(id) => heap.get_list(&id).cloned(),
+                _ => None,
+            };
+            let items = items.ok_or_else(|| RuntimeError::new("Argument must be a list."))?;
+            let mut counts: Vec<(Value, Value)> = Vec::new();
+            for item in items {
+                match counts.iter_mut().find(|(key, _)| *key == item) {
+                    Some((_, Value::Number(count))) => *count += 1.0,
+                    _ => counts.push((item, Value::from(1))),
+                }
+            }
+            let map = heap.new_map(&counts)?;
+            let keys = heap.get_map(&map).unwrap().entries().iter().map(|(key, _)| *key).collect();
+            let keys = heap.new_list(keys);
+            Ok(Value::Object(heap.new_list(vec![Value::Object(map), Value::Object(keys)])))
+        }
+
+        let mut vm = Vm::new();
+        vm.define_native("tally", Arity::Fixed(1), tally);
+        assert_eq!(vm.interpret("var result = tally([\"a\", \"b\", \"a\"]);"), VmResult::Ok);
+        let result = vm.get_global("result").unwrap();
+        assert_eq!(vm.format_value(&result), "[{a: 2, b: 1}, [a, b]]");
+        assert_eq!(
+            error_message(vm.interpret("tally([[]]);")),
+            Some("Map keys must be strings, numbers, booleans or nil.".to_string())
+        );
+    }
+
+    #[test]
+    fn state_persists_across_interpret_calls() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret("var count = 1;"), VmResult::Ok);
+        assert_eq!(vm.interpret("fun bump() { count = count + 1; }"), VmResult::Ok);
+        assert_eq!(vm.interpret("bump(); bump();"), VmResult::Ok);
+        assert_eq!(vm.get_global("count").map(f64::try_from), Some(Ok(3.0)));
+
+        // A runtime error unwinds the stack but keeps what was defined
+        assert_eq!(error_message(vm.interpret("undefined;")), Some("Undefined variable 'undefined'.".to_string()));
+        assert_eq!(vm.interpret("bump();"), VmResult::Ok);
+        assert_eq!(vm.get_global("count").map(f64::try_from), Some(Ok(4.0)));
+    }
+
+    #[test]
+    fn script_arguments() {
+        let mut vm = Vm::new();
+        vm.set_args(&["first".to_string(), "second".to_string()]);
+        assert_eq!(vm.interpret("var count = argc(); var last = argv(1); var past = argv(2);"), VmResult::Ok);
+        assert_eq!(vm.get_global("count").map(f64::try_from), Some(Ok(2.0)));
+        let last = vm.get_global("last").unwrap();
+        assert_eq!(vm.get_string(&last), Some("second"));
+        assert_eq!(vm.get_global("past"), Some(Value::Nil));
+        assert_eq!(
+            error_message(vm.interpret("argv(-1);")),
+            Some("Argument must be a non-negative integer.".to_string())
+        );
+    }
+
+    #[test]
+    fn large_integers_are_exact() {
+        let mut vm = Vm::new();
+        let source = "var big = 16777216 + 1; var bigger = 9007199254740991 - 1; var cents = 0.1 * 3;";
+        assert_eq!(vm.interpret(source), VmResult::Ok);
+        assert_eq!(vm.get_global("big").map(f64::try_from), Some(Ok(16777217.0)));
+        assert_eq!(vm.get_global("bigger").map(f64::try_from), Some(Ok(9007199254740990.0)));
+        assert_eq!(vm.get_global("cents").unwrap().to_string(), "0.30000000000000004");
+        assert_eq!(vm.get_global("big").unwrap().to_string(), "16777217");
+    }
+
+    #[test]
+    fn long_constants() {
+        // Past 256 constants literals need a three byte operand
+        let sum: Vec<String> = (0..300).map(|n| n.to_string()).collect();
+        let source = format!("var total = {};", sum.join(" + "));
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&source), VmResult::Ok);
+        assert_eq!(vm.get_global("total").map(f64::try_from), Some(Ok(44850.0)));
+    }
+
+    #[test]
+    fn long_globals_locals_and_upvalues() {
+        let globals: String = (0..300).map(|n| format!("var g{} = {};\n", n, n)).collect();
+        let locals: String = (0..300).map(|n| format!("  var l{} = {};\n", n, n)).collect();
+        let source = format!(
+            "{}class Box {{}}\nfun make() {{\n{}  fun last() {{ return l299 + g299; }}\n  l299 = l299 + 1;\n  return last;\n}}\nvar box = Box();\nbox.total = make()();\nvar total = box.total;\n",
+            globals, locals
+        );
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&source), VmResult::Ok);
+        assert_eq!(vm.get_global("total").map(f64::try_from), Some(Ok(599.0)));
+
+        let listing = vm.disassemble(&source).unwrap();
+        for op_code in ["DefineGlobalLong", "GetGlobalLong", "GetLocalLong", "SetLocalLong", "SetPropertyLong"] {
+            assert!(listing.contains(op_code), "no {} in the listing", op_code);
+        }
+        assert!(listing.contains("local 300"));
+    }
+
+    #[test]
+    fn check_compiles_without_running() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.check("var ran = true;"), Ok(()));
+        assert_eq!(vm.get_global("ran"), None);
+        assert!(vm.check("print ;").is_err());
+    }
+
+    #[test]
+    fn runs_precompiled_bytecode() {
+        let source = test_file("test/disasm/classes.lox");
+        let bytes = Vm::new().compile_bytecode(&source).expect("Should compile");
+        assert!(is_bytecode(&bytes));
+
+        // A fresh VM runs it without ever seeing the source
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret_bytecode(&bytes), VmResult::Ok);
+        assert!(vm.get_global("Square").is_some());
+        assert_eq!(
+            vm.interpret_bytecode(source.as_bytes()),
+            VmResult::LoadError(LoadError::NotBytecode)
+        );
+    }
+
+    #[test]
+    fn runtime_error_stack_trace() {
+        let source = test_file("test/test-42-error.lox");
+        let error = match interpret(&source) {
+            VmResult::RuntimeError(error) => error,
+            result => panic!("Expected a runtime error, got {:?}", result),
+        };
+        assert_eq!(error.message, "Operand must be a number.");
+        let trace: Vec<String> = error.trace.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(
+            trace,
+            vec![
+                "[line 6] in c()",
+                "[line 3] in b()",
+                "[line 1] in a()",
+                "[line 9] in script",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_compile_error() {
+        let source = test_file("test/test-43-error.lox");
+        let error = match interpret(&source) {
+            VmResult::CompileError(error) => error,
+            result => panic!("Expected a compile error, got {:?}", result),
+        };
+        let messages: Vec<String> = error.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "[line 1] Error at ';': Expect expression.",
+                "[line 2] Error at '=': Expect variable name",
+                "[line 3] Error at ';': Expect a ')' after expression.",
+                "[line 6] Error at ';': Expect property name after '.'.",
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_input_never_panics() {
+        let sources = [
+            "", "(", ")", "{", "}", "print", "var", "var x =", "fun", "fun (", "class {",
+            "class A < {}", "super.x;", "return", "1 +", "\"unterminated", "a.b.c = ;",
+            "for (;;", "while (", "if (true) else", "{ var a = a; ", "!", "-", "x = = 1;",
+            "match (", "match (1) { case", "match (1) { case -", "break", "continue",
+            "try", "try {", "try {} catch", "try {} catch (", "throw", "try {} finally",
+        ];
+        for source in sources.iter() {
+            match interpret(source) {
+                VmResult::CompileError(error) => assert!(!error.diagnostics.is_empty()),
+                VmResult::Ok | VmResult::RuntimeError(_) => (),
+                result => panic!("Unexpected result for {:?}: {:?}", source, result),
+            }
+        }
+    }
+
+    /// Compares each `test/disasm/*.lox` listing with the `.out` file next to
+    /// it. Run with `UPDATE_SNAPSHOTS=1` to rewrite them after an intended
+    /// change.
+    #[test]
+    fn disassembly_snapshots() {
+        let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+        for name in ["control_flow", "closures", "classes", "exceptions"].iter() {
+            let source = test_file(&format!("test/disasm/{}.lox", name));
+            let listing = Vm::new().disassemble(&source).expect("Snapshot should compile");
+            let snapshot = format!("test/disasm/{}.out", name);
+            if update {
+                std::fs::write(&snapshot, &listing).expect("Couldn't write snapshot");
+            } else {
+                assert_eq!(listing, test_file(&snapshot), "{} differs from its snapshot", name);
+            }
+        }
+    }
+
+    #[test]
+    fn list_indexing() {
+        assert_eq!(fixture_result("test/test-54.lox"), "[1, 4, 3]");
+    }
+
+    #[test]
+    fn list_push_and_insert() {
+        assert_eq!(fixture_result("test/test-55.lox"), "[1, nil, [2]]");
+    }
+
+    #[test]
+    fn list_pop_and_remove() {
+        assert_eq!(fixture_result("test/test-56.lox"), "[3, 1, [2]]");
+    }
+
+    #[test]
+    fn list_length() {
+        assert_eq!(fixture_result("test/test-57.lox"), "3");
+    }
+
+    #[test]
+    fn list_slices() {
+        assert_eq!(fixture_result("test/test-58.lox"), "[[2, 3], []]");
+    }
+
+    #[test]
+    fn list_containing_itself() {
+        assert_eq!(fixture_result("test/test-59.lox"), "[1, [...]]");
+    }
+
+    #[test]
+    fn list_index_past_the_end_error() {
+        let source = test_file("test/test-60-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("List index out of range.".to_string()));
+    }
+
+    #[test]
+    fn list_index_before_the_start_error() {
+        let source = test_file("test/test-61-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("List index out of range.".to_string()));
+    }
+
+    #[test]
+    fn list_index_not_an_integer_error() {
+        let source = test_file("test/test-62-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("List index must be an integer.".to_string()));
+    }
+
+    #[test]
+    fn indexing_a_string_error() {
+        let source = test_file("test/test-63-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Only lists and maps can be indexed.".to_string()));
+    }
+
+    #[test]
+    fn pop_empty_list_error() {
+        let source = test_file("test/test-64-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Can't pop from an empty list.".to_string()));
+    }
+
+    #[test]
+    fn push_onto_non_list_error() {
+        let source = test_file("test/test-65-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("First argument must be a list.".to_string()));
+    }
+
+    #[test]
+    fn insert_out_of_range_error() {
+        let source = test_file("test/test-66-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("List index out of range.".to_string()));
+    }
+
+    #[test]
+    fn unclosed_list_literal_error() {
+        assert_eq!(compile_error("test/test-67-error.lox"), "Expect ']' after list elements.");
+    }
+
+    #[test]
+    fn unclosed_list_index_error() {
+        assert_eq!(compile_error("test/test-68-error.lox"), "Invalid assignment target.");
+    }
+
+    #[test]
+    fn map_literals_and_assignment() {
+        assert_eq!(fixture_result("test/test-69.lox"), "{a: 2, 2: two, true: nil}");
+    }
+
+    #[test]
+    fn map_negative_zero_key() {
+        assert_eq!(fixture_result("test/test-70.lox"), "[0, {-0: 0}]");
+    }
+
+    #[test]
+    fn map_has_and_delete() {
+        assert_eq!(fixture_result("test/test-71.lox"), "[true, true, false]");
+    }
+
+    #[test]
+    fn map_keys_values_and_length() {
+        assert_eq!(fixture_result("test/test-72.lox"), "[[a, true], [1, nil], 2]");
+    }
+
+    #[test]
+    fn empty_map_in_a_block() {
+        assert_eq!(fixture_result("test/test-73.lox"), "0");
+    }
+
+    #[test]
+    fn map_keys_built_at_runtime() {
+        assert_eq!(fixture_result("test/test-74.lox"), "1");
+    }
+
+    #[test]
+    fn undefined_map_key_error() {
+        let source = test_file("test/test-75-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Undefined key 'x'.".to_string()));
+    }
+
+    #[test]
+    fn list_as_map_key_error() {
+        let source = test_file("test/test-76-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Map keys must be strings, numbers, booleans or nil.".to_string()));
+    }
+
+    #[test]
+    fn map_as_map_key_error() {
+        let source = test_file("test/test-77-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("Map keys must be strings, numbers, booleans or nil.".to_string()));
+    }
+
+    #[test]
+    fn nan_map_key_error() {
+        let source = test_file("test/test-78-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("NaN can't be a map key.".to_string()));
+    }
+
+    #[test]
+    fn keys_of_non_map_error() {
+        let source = test_file("test/test-79-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("First argument must be a map.".to_string()));
+    }
+
+    #[test]
+    fn map_entry_without_colon_error() {
+        assert_eq!(compile_error("test/test-80-error.lox"), "Expect ':' after map key.");
+    }
+
+    #[test]
+    fn break_and_continue_in_a_for_loop() {
+        assert_eq!(fixture_result("test/test-81.lox"), "[0, 1, 3, 4]");
+    }
+
+    #[test]
+    fn continue_from_a_nested_block() {
+        assert_eq!(fixture_result("test/test-82.lox"), "4");
+    }
+
+    #[test]
+    fn break_out_of_an_endless_for_loop() {
+        assert_eq!(fixture_result("test/test-83.lox"), "3");
+    }
+
+    #[test]
+    fn break_closes_captured_locals() {
+        assert_eq!(fixture_result("test/test-84.lox"), "1");
+    }
+
+    #[test]
+    fn break_outside_a_loop_error() {
+        assert_eq!(compile_error("test/test-85-error.lox"), "Can't use 'break' outside of a loop.");
+    }
+
+    #[test]
+    fn continue_outside_a_loop_error() {
+        assert_eq!(compile_error("test/test-86-error.lox"), "Can't use 'continue' outside of a loop.");
+    }
+
+    #[test]
+    fn break_in_a_function_inside_a_loop_error() {
+        assert_eq!(compile_error("test/test-87-error.lox"), "Can't use 'break' outside of a loop.");
+    }
+
+    #[test]
+    fn match_arms_with_several_values() {
+        assert_eq!(fixture_result("test/test-88.lox"), "[small, small, falsey, falsey]");
+    }
+
+    #[test]
+    fn match_negative_literal() {
+        assert_eq!(fixture_result("test/test-89.lox"), "negative");
+    }
+
+    #[test]
+    fn match_block_arm() {
+        assert_eq!(fixture_result("test/test-90.lox"), "ex");
+    }
+
+    #[test]
+    fn match_default_arm() {
+        assert_eq!(fixture_result("test/test-91.lox"), "[other, other]");
+    }
+
+    #[test]
+    fn break_and_continue_inside_match() {
+        assert_eq!(fixture_result("test/test-92.lox"), "3");
+    }
+
+    #[test]
+    fn match_without_a_matching_arm() {
+        assert_eq!(fixture_result("test/test-93.lox"), "0");
+    }
+
+    #[test]
+    fn duplicate_case_in_a_later_arm_error() {
+        assert_eq!(compile_error("test/test-94-error.lox"), "Duplicate case value.");
+    }
+
+    #[test]
+    fn duplicate_case_negative_zero_error() {
+        assert_eq!(compile_error("test/test-95-error.lox"), "Duplicate case value.");
+    }
+
+    #[test]
+    fn duplicate_case_in_the_same_arm_error() {
+        assert_eq!(compile_error("test/test-96-error.lox"), "Duplicate case value.");
+    }
+
+    #[test]
+    fn non_literal_case_error() {
+        assert_eq!(compile_error("test/test-97-error.lox"), "Expect a literal or '_' after 'case'.");
+    }
+
+    #[test]
+    fn arm_after_catch_all_error() {
+        assert_eq!(compile_error("test/test-98-error.lox"), "A match can't have arms after a catch-all.");
+    }
+
+    #[test]
+    fn arm_without_arrow_error() {
+        assert_eq!(compile_error("test/test-99-error.lox"), "Expect '=>' before arm body.");
+    }
+
+    #[test]
+    fn throw_and_catch() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&test_file("test/test-100.lox")), VmResult::Ok);
+        let result = vm.get_global("result").unwrap();
+        assert_eq!(vm.get_string(&result), Some("boom"));
+        // Nothing is left behind for the next script to trip over
+        assert_eq!(vm.interpret("var after = 1;"), VmResult::Ok);
+    }
+
+    #[test]
+    fn runtime_errors_are_caught_as_error_instances() {
+        assert_eq!(fixture_result("test/test-101.lox"), "[Operand must be a number., 1]");
+    }
+
+    #[test]
+    fn errors_are_caught_across_calls() {
+        assert_eq!(fixture_result("test/test-102.lox"), "[Undefined variable 'missing'., 4]");
+    }
+
+    #[test]
+    fn finally_runs_while_an_exception_propagates() {
+        assert_eq!(fixture_result("test/test-103.lox"), "[inner, 1]");
+    }
+
+    #[test]
+    fn finally_runs_on_return() {
+        assert_eq!(fixture_result("test/test-104.lox"), "[cleanup, returned]");
+    }
+
+    #[test]
+    fn finally_runs_on_break_and_continue() {
+        assert_eq!(fixture_result("test/test-105.lox"), "[0, 1]");
+    }
+
+    #[test]
+    fn throw_from_a_catch() {
+        assert_eq!(fixture_result("test/test-106.lox"), "ab");
+    }
+
+    #[test]
+    fn closures_capture_the_exception() {
+        assert_eq!(fixture_result("test/test-107.lox"), "closed over");
+    }
+
+    #[test]
+    fn uncaught_throw_reports_its_line() {
+        let error = match interpret(&test_file("test/test-53-error.lox")) {
+            VmResult::RuntimeError(error) => error,
+            result => panic!("Expected a runtime error, got {:?}", result),
+        };
+        assert_eq!(error.message, "boom");
+        let trace: Vec<String> = error.trace.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(trace, vec!["[line 2] in fail()", "[line 5] in script"]);
+    }
+
+    #[test]
+    fn uncaught_thrown_string_error() {
+        let source = test_file("test/test-108-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("boom".to_string()));
+    }
+
+    #[test]
+    fn uncaught_thrown_list_error() {
+        let source = test_file("test/test-109-error.lox");
+        let result = interpret(&source);
+        assert_eq!(error_message(result), Some("[1]".to_string()));
+    }
+
+    #[test]
+    fn rethrow_after_finally_keeps_the_trace() {
+        let error = match interpret(&test_file("test/test-110-error.lox")) {
+            VmResult::RuntimeError(error) => error,
+            result => panic!("Expected a runtime error, got {:?}", result),
+        };
+        assert_eq!(error.message, "Operand must be a number.");
+        let trace: Vec<String> = error.trace.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(trace, vec!["[line 3] in f()", "[line 8] in script"]);
+    }
+
+    #[test]
+    fn try_without_catch_or_finally_error() {
+        assert_eq!(compile_error("test/test-111-error.lox"), "Expect 'catch' or 'finally' after try block.");
+    }
+
+    #[test]
+    fn catch_without_parentheses_error() {
+        assert_eq!(compile_error("test/test-112-error.lox"), "Expect '(' after 'catch'.");
+    }
+
+    #[test]
+    fn catch_without_a_variable_error() {
+        assert_eq!(compile_error("test/test-113-error.lox"), "Expect exception variable name.");
+    }
+
+    #[test]
+    fn try_without_a_block_error() {
+        assert_eq!(compile_error("test/test-114-error.lox"), "Expect '{' after 'try'.");
+    }
+
+    #[test]
+    fn throw_without_a_value_error() {
+        assert_eq!(compile_error("test/test-115-error.lox"), "Expect expression.");
+    }
+
+    #[test]
+    fn caught_throw_does_not_leak_into_a_later_one() {
+        let error = match interpret(&test_file("test/test-116-error.lox")) {
+            VmResult::RuntimeError(error) => error,
+            result => panic!("Expected a runtime error, got {:?}", result),
+        };
+        assert_eq!(error.message, "negative");
+        let trace: Vec<String> = error.trace.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(trace, vec!["[line 2] in check()", "[line 9] in script"]);
+    }
+
+    #[test]
+    fn caught_throw_does_not_leak_into_the_next_script() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret(&test_file("test/test-117.lox")), VmResult::Ok);
+        let error = match vm.interpret("check(-2);") {
+            VmResult::RuntimeError(error) => error,
+            result => panic!("Expected a runtime error, got {:?}", result),
+        };
+        let trace: Vec<String> = error.trace.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(trace, vec!["[line 2] in check()", "[line 1] in script"]);
+    }
+
+    #[test]
+    fn mixed_number_arithmetic_can_be_caught() {
+        assert_eq!(fixture_result("test/test-49.lox"), "Operands must be numbers.");
+    }
+
+    #[test]
+    fn mixed_addition_can_be_caught() {
+        assert_eq!(fixture_result("test/test-50.lox"), "Operands must be two numbers or two strings.");
+    }
 }
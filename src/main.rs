@@ -1,16 +1,136 @@
 use std::env;
-use std::io;
-use rlox::{repl, run_file};
-
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-
-    match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
-        _ => {
-            println!("Usage: lox [script]");
-            std::process::exit(64)
+use rlox::{check_file, compile_file, disassemble_file, repl, run_file, run_source, Options};
+
+const USAGE: &str = "Usage: rlox [options] [command]
+
+Commands:
+    repl                      Start an interactive session (the default)
+    run <script> [args...]    Run a script or a compiled .loxc file
+    check <script>            Compile a script and report errors without running it
+    compile <script> [-o out] Save a script's bytecode to a .loxc file
+    disasm <script>           Print the bytecode of every function in a script
+    -e <source> [args...]     Run source given on the command line
+    <script> [args...]        Same as run
+
+Options:
+    --error-format human|json Print errors for people or as JSON lines
+    --print-code              Print the bytecode of each function once compiled
+    --trace-exec              Print every instruction and the stack as it runs
+    --trace-calls             Print every call and return
+    --trace-function <name>   Only trace inside the function <name> ('script' for
+                              the top level)
+    --trace-file <path>       Write the trace to <path> instead of stderr
+
+A script of '-' is read from standard input. Arguments after the script are
+available to it through argc() and argv(n).";
+
+enum Command {
+    Repl,
+    Run(String, Vec<String>),
+    Check(String),
+    Compile(String, Option<String>),
+    Disasm(String),
+    Eval(String, Vec<String>),
+}
+
+fn main() {
+    let (command, options) = parse_args(env::args().skip(1).collect());
+
+    let result = match &command {
+        Command::Repl => repl(&options).map(|_| 0),
+        Command::Run(path, args) => run_file(path, args, &options),
+        Command::Check(path) => check_file(path, &options),
+        Command::Compile(path, output) => compile_file(path, output.as_deref(), &options),
+        Command::Disasm(path) => disassemble_file(path, &options),
+        Command::Eval(source, args) => run_source("<eval>", source, args, &options),
+    };
+
+    match result {
+        Ok(code) => std::process::exit(code),
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(74)
+        }
+    }
+}
+
+/// Options come before the command; everything after the script belongs to
+/// the script.
+fn parse_args(args: Vec<String>) -> (Command, Options) {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+
+    let command = loop {
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None => break Command::Repl,
+        };
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || match inline_value.clone().or_else(|| args.next()) {
+            Some(value) => value,
+            None => usage_error(&format!("Missing value for {}.", name)),
+        };
+
+        match name.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0)
+            }
+            "--error-format" => {
+                options.error_format = match value().parse() {
+                    Ok(format) => format,
+                    Err(message) => usage_error(&message),
+                }
+            }
+            "--print-code" => options.trace.code = true,
+            "--trace-exec" => options.trace.exec = true,
+            "--trace-calls" => options.trace.calls = true,
+            "--trace-function" => options.trace.function = Some(value()),
+            "--trace-file" => options.trace_file = Some(value()),
+            _ => break parse_command(arg, &mut args),
         }
+    };
+    (command, options)
+}
+
+fn parse_command(command: String, args: &mut impl Iterator<Item = String>) -> Command {
+    let mut script = |name: &str| match args.next() {
+        Some(script) => script,
+        None => usage_error(&format!("'{}' needs a script.", name)),
+    };
+
+    let command = match command.as_str() {
+        "repl" => Command::Repl,
+        "run" => Command::Run(script("run"), Vec::new()),
+        "check" => Command::Check(script("check")),
+        "compile" => Command::Compile(script("compile"), None),
+        "disasm" => Command::Disasm(script("disasm")),
+        "-e" => Command::Eval(script("-e"), Vec::new()),
+        arg if arg.starts_with('-') && arg != "-" => {
+            usage_error(&format!("Unknown option '{}'.", arg))
+        }
+        _ => Command::Run(command, Vec::new()),
+    };
+
+    let rest: Vec<String> = args.collect();
+    match command {
+        Command::Run(path, _) => Command::Run(path, rest),
+        Command::Eval(source, _) => Command::Eval(source, rest),
+        Command::Compile(path, _) => match rest.as_slice() {
+            [] => Command::Compile(path, None),
+            [flag, output] if flag == "-o" => Command::Compile(path, Some(output.clone())),
+            _ => usage_error("Expected only '-o <path>' after the script."),
+        },
+        command if rest.is_empty() => command,
+        _ => usage_error("Too many arguments."),
     }
 }
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", USAGE);
+    std::process::exit(64)
+}
@@ -0,0 +1,152 @@
+use std::fmt::Write;
+
+use crate::compiler::{CompilerError, Diagnostic};
+use crate::core::{RuntimeError, Span, TraceFrame};
+use crate::report::Report;
+
+/// Machine readable diagnostics, one JSON object per line, for editors and
+/// CI to consume instead of the human format.
+impl<'a> Report<'a> {
+    pub fn compile_error_json(&self, error: &CompilerError) -> String {
+        error
+            .diagnostics
+            .iter()
+            .map(|diagnostic| self.diagnostic_json(diagnostic) + "\n")
+            .collect()
+    }
+
+    pub fn diagnostic_json(&self, diagnostic: &Diagnostic) -> String {
+        let labels: Vec<String> = diagnostic
+            .labels
+            .iter()
+            .map(|label| {
+                format!(
+                    "{{\"message\":{},{}}}",
+                    json_string(&label.message),
+                    self.position_json(Span::new(label.start, label.length))
+                )
+            })
+            .collect();
+        format!(
+            "{{\"severity\":\"error\",\"kind\":\"compile\",\"message\":{},\"file\":{},{},\"labels\":[{}],\"trace\":[]}}",
+            json_string(&diagnostic.message),
+            json_string(self.path),
+            self.position_json(Span::new(diagnostic.start, diagnostic.length)),
+            labels.join(",")
+        )
+    }
+
+    /// A runtime error is reported at the innermost frame of its trace.
+    pub fn runtime_error_json(&self, error: &RuntimeError) -> String {
+        let position = match error.trace.first() {
+            Some(frame) if self.has_source => self.position_json(frame.span),
+            Some(frame) => format!("\"line\":{},\"column\":null,\"length\":0", frame.line),
+            None => String::from("\"line\":null,\"column\":null,\"length\":0"),
+        };
+        let trace: Vec<String> = error.trace.iter().map(trace_frame_json).collect();
+        format!(
+            "{{\"severity\":\"error\",\"kind\":\"runtime\",\"message\":{},\"file\":{},{},\"labels\":[],\"trace\":[{}]}}\n",
+            json_string(&error.message),
+            json_string(self.path),
+            position,
+            trace.join(",")
+        )
+    }
+
+    /// An error about the file as a whole, such as bytecode that won't load.
+    pub fn file_error_json(&self, kind: &str, message: &str) -> String {
+        format!(
+            "{{\"severity\":\"error\",\"kind\":{},\"message\":{},\"file\":{},\"line\":null,\"column\":null,\"length\":0,\"labels\":[],\"trace\":[]}}\n",
+            json_string(kind),
+            json_string(message),
+            json_string(self.path)
+        )
+    }
+
+    /// Line and column are 1-based; the column and length count characters.
+    fn position_json(&self, span: Span) -> String {
+        let start = self.clamp(span.start);
+        let end = self.clamp(span.start + span.length);
+        format!(
+            "\"line\":{},\"column\":{},\"length\":{}",
+            self.line_of(start),
+            self.column_of(start),
+            self.source[start..end].chars().count()
+        )
+    }
+}
+
+fn trace_frame_json(frame: &TraceFrame) -> String {
+    let function = match &frame.function {
+        Some(name) => json_string(name),
+        None => String::from("null"),
+    };
+    format!("{{\"function\":{},\"line\":{}}}", function, frame.line)
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Vm, VmResult};
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+    }
+
+    #[test]
+    fn compile_errors_are_json_lines() {
+        let source = "{\n  var a = 1;\n  var a = 2;\n}\nprint ;\n";
+        let report = Report::new("test.lox", source);
+        let error = match Vm::new().interpret(source) {
+            VmResult::CompileError(error) => error,
+            result => panic!("expected a compile error, got {:?}", result),
+        };
+        let expected = concat!(
+            "{\"severity\":\"error\",\"kind\":\"compile\",",
+            "\"message\":\"Already a variable with this name in this scope.\",",
+            "\"file\":\"test.lox\",\"line\":3,\"column\":7,\"length\":1,",
+            "\"labels\":[{\"message\":\"previously declared here\",\"line\":2,\"column\":7,\"length\":1}],",
+            "\"trace\":[]}\n",
+            "{\"severity\":\"error\",\"kind\":\"compile\",\"message\":\"Expect expression.\",",
+            "\"file\":\"test.lox\",\"line\":5,\"column\":7,\"length\":1,\"labels\":[],\"trace\":[]}\n",
+        );
+        assert_eq!(report.compile_error_json(&error), expected);
+    }
+
+    #[test]
+    fn runtime_errors_carry_the_trace() {
+        let source = "fun f() {\n  return -nil;\n}\nf();\n";
+        let report = Report::new("test.lox", source);
+        let error = match Vm::new().interpret(source) {
+            VmResult::RuntimeError(error) => error,
+            result => panic!("expected a runtime error, got {:?}", result),
+        };
+        let expected = concat!(
+            "{\"severity\":\"error\",\"kind\":\"runtime\",\"message\":\"Operand must be a number.\",",
+            "\"file\":\"test.lox\",\"line\":2,\"column\":10,\"length\":1,\"labels\":[],",
+            "\"trace\":[{\"function\":\"f\",\"line\":2},{\"function\":null,\"line\":4}]}\n",
+        );
+        assert_eq!(report.runtime_error_json(&error), expected);
+    }
+}
@@ -0,0 +1,321 @@
+use std::str::FromStr;
+
+use colored::*;
+
+use crate::compiler::{CompilerError, Diagnostic};
+use crate::core::{RuntimeError, Span};
+
+mod json;
+
+/// How errors are written out: for people, or as JSON lines for tools.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("Unknown error format '{}'.", s)),
+        }
+    }
+}
+
+/// Renders errors against the source they came from: the offending line,
+/// its line and column, and an underline beneath the exact span.
+pub struct Report<'a> {
+    path: &'a str,
+    source: &'a str,
+    /// False for precompiled scripts, whose source isn't around to show.
+    has_source: bool,
+    color: bool,
+}
+
+/// One underlined span in a rendered snippet.
+struct Mark<'a> {
+    span: Span,
+    message: &'a str,
+    primary: bool,
+}
+
+impl<'a> Report<'a> {
+    /// Colors output when the terminal supports it, honouring the usual
+    /// `NO_COLOR` and `CLICOLOR_FORCE` environment variables.
+    pub fn new(path: &'a str, source: &'a str) -> Self {
+        Report {
+            path,
+            source,
+            has_source: true,
+            color: colored::control::SHOULD_COLORIZE.should_colorize(),
+        }
+    }
+
+    /// Reports on a precompiled script, by line number only.
+    pub fn without_source(path: &'a str) -> Self {
+        Report {
+            has_source: false,
+            ..Report::new(path, "")
+        }
+    }
+
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn compile_error(&self, error: &CompilerError) -> String {
+        let rendered: Vec<String> = error
+            .diagnostics
+            .iter()
+            .map(|diagnostic| self.diagnostic(diagnostic))
+            .collect();
+        rendered.join("\n")
+    }
+
+    pub fn diagnostic(&self, diagnostic: &Diagnostic) -> String {
+        let mut marks = vec![Mark {
+            span: Span::new(diagnostic.start, diagnostic.length),
+            message: "",
+            primary: true,
+        }];
+        for label in &diagnostic.labels {
+            marks.push(Mark {
+                span: Span::new(label.start, label.length),
+                message: &label.message,
+                primary: false,
+            });
+        }
+
+        let mut out = self.header("error", &diagnostic.message);
+        out.push_str(&self.snippet(&marks));
+        out
+    }
+
+    /// Renders a runtime error pointing at the instruction that failed,
+    /// followed by the stack trace.
+    pub fn runtime_error(&self, error: &RuntimeError) -> String {
+        let mut out = self.header("runtime error", &error.message);
+        if let Some(frame) = error.trace.first().filter(|_| self.has_source) {
+            let marks = [Mark {
+                span: frame.span,
+                message: "",
+                primary: true,
+            }];
+            out.push_str(&self.snippet(&marks));
+        }
+        out.push_str(&self.trace(error));
+        out
+    }
+
+    /// Renders a runtime error without a snippet, for when the failing code
+    /// may have been compiled from some other source than this one.
+    pub fn runtime_error_trace(&self, error: &RuntimeError) -> String {
+        let mut out = self.header("runtime error", &error.message);
+        out.push_str(&self.trace(error));
+        out
+    }
+
+    /// An error about the file as a whole, such as bytecode that won't load.
+    pub fn file_error(&self, message: &str) -> String {
+        let mut out = self.header("error", message);
+        out.push_str(&format!(" {} {}\n", self.paint("-->", Style::Gutter), self.path));
+        out
+    }
+
+    fn trace(&self, error: &RuntimeError) -> String {
+        error
+            .trace
+            .iter()
+            .map(|frame| format!("{} {}\n", self.paint("=", Style::Gutter), frame))
+            .collect()
+    }
+
+    fn header(&self, severity: &str, message: &str) -> String {
+        format!(
+            "{}{}\n",
+            self.paint(severity, Style::Error),
+            self.paint(&format!(": {}", message), Style::Bold)
+        )
+    }
+
+    fn snippet(&self, marks: &[Mark]) -> String {
+        let mut lines: Vec<(usize, usize, &Mark)> = marks
+            .iter()
+            .map(|mark| {
+                let start = self.clamp(mark.span.start);
+                (self.line_of(start), start, mark)
+            })
+            .collect();
+        lines.sort_by_key(|(line, start, _)| (*line, *start));
+
+        let width = lines.iter().map(|(line, _, _)| line.to_string().len()).max().unwrap_or(1);
+        let gutter = " ".repeat(width);
+        let primary = lines.iter().find(|(_, _, mark)| mark.primary).unwrap_or(&lines[0]);
+
+        let mut out = format!(
+            "{}{} {}:{}:{}\n",
+            gutter,
+            self.paint("-->", Style::Gutter),
+            self.path,
+            primary.0,
+            self.column_of(primary.1)
+        );
+        out.push_str(&format!("{} {}\n", gutter, self.paint("|", Style::Gutter)));
+
+        let mut last_line = None;
+        for (line, start, mark) in &lines {
+            let (line_start, text) = self.line_text(*start);
+            if last_line != Some(*line) {
+                let number = format!("{:>width$} |", line, width = width);
+                out.push_str(&format!("{} {}\n", self.paint(&number, Style::Gutter), text));
+                last_line = Some(*line);
+            }
+
+            let padding = self.source[line_start..*start].chars().count();
+            let end = (*start + mark.span.length).min(line_start + text.len());
+            let length = self.source[*start..self.clamp(end)].chars().count().max(1);
+            let (underline, style) = if mark.primary {
+                ("^".repeat(length), Style::Error)
+            } else {
+                ("-".repeat(length), Style::Label)
+            };
+            let mut annotation = underline;
+            if !mark.message.is_empty() {
+                annotation.push(' ');
+                annotation.push_str(mark.message);
+            }
+            out.push_str(&format!(
+                "{} {} {}{}\n",
+                gutter,
+                self.paint("|", Style::Gutter),
+                " ".repeat(padding),
+                self.paint(&annotation, style)
+            ));
+        }
+        out
+    }
+
+    /// Keeps an offset inside the source and on a character boundary.
+    fn clamp(&self, offset: usize) -> usize {
+        let mut offset = offset.min(self.source.len());
+        while !self.source.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+
+    fn line_of(&self, offset: usize) -> usize {
+        self.source[..offset].matches('\n').count() + 1
+    }
+
+    fn column_of(&self, offset: usize) -> usize {
+        let (line_start, _) = self.line_text(offset);
+        self.source[line_start..offset].chars().count() + 1
+    }
+
+    /// The byte offset and text of the line containing `offset`.
+    fn line_text(&self, offset: usize) -> (usize, &'a str) {
+        let start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = self.source[offset..]
+            .find('\n')
+            .map_or(self.source.len(), |i| offset + i);
+        (start, self.source[start..end].trim_end_matches('\r'))
+    }
+
+    fn paint(&self, text: &str, style: Style) -> String {
+        if !self.color {
+            return text.to_string();
+        }
+        match style {
+            Style::Error => text.red().bold().to_string(),
+            Style::Label => text.blue().bold().to_string(),
+            Style::Gutter => text.blue().to_string(),
+            Style::Bold => text.bold().to_string(),
+        }
+    }
+}
+
+enum Style {
+    Error,
+    Label,
+    Gutter,
+    Bold,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Vm, VmResult};
+
+    fn render(source: &str) -> String {
+        let report = Report::new("test.lox", source).color(false);
+        match Vm::new().interpret(source) {
+            VmResult::CompileError(error) => report.compile_error(&error),
+            VmResult::RuntimeError(error) => report.runtime_error(&error),
+            result => panic!("expected an error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn underlines_compile_errors() {
+        let rendered = render("var a = 1;\nprint a +;\n");
+        let expected = concat!(
+            "error: Expect expression.\n",
+            " --> test.lox:2:10\n",
+            "  |\n",
+            "2 | print a +;\n",
+            "  |          ^\n",
+        );
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn labels_previous_declarations() {
+        let rendered = render("{\n  var value = 1;\n  var value = 2;\n}\n");
+        let expected = concat!(
+            "error: Already a variable with this name in this scope.\n",
+            " --> test.lox:3:7\n",
+            "  |\n",
+            "2 |   var value = 1;\n",
+            "  |       ----- previously declared here\n",
+            "3 |   var value = 2;\n",
+            "  |       ^^^^^\n",
+        );
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn underlines_runtime_errors_with_trace() {
+        let rendered = render("fun f() {\n  return -\"x\";\n}\nf();\n");
+        let expected = concat!(
+            "runtime error: Operand must be a number.\n",
+            " --> test.lox:2:10\n",
+            "  |\n",
+            "2 |   return -\"x\";\n",
+            "  |          ^\n",
+            "= [line 2] in f()\n",
+            "= [line 4] in script\n",
+        );
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn counts_columns_in_characters() {
+        let report = Report::new("test.lox", "print \"héllo\" + ;").color(false);
+        let diagnostic = Diagnostic {
+            message: String::from("Expect expression."),
+            line: 1,
+            start: 17,
+            length: 1,
+            location: crate::Location::Lexeme(String::from(";")),
+            labels: Vec::new(),
+        };
+        assert!(report.diagnostic(&diagnostic).contains("test.lox:1:17"));
+    }
+}
@@ -10,7 +10,7 @@ pub struct Scanner<'a> {
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(source: &'a str) -> Scanner {
+    pub fn new(source: &'a str) -> Scanner<'a> {
         Scanner {
             source,
             start: 0,
@@ -30,11 +30,11 @@ impl<'a> Scanner<'a> {
 
         let c = self.advance();
 
-        if c.map_or(false, is_alpha) {
+        if c.is_some_and(is_alpha) {
             return self.identifier();
         }
 
-        if c.map_or(false, is_digit) {
+        if c.is_some_and(is_digit) {
             return self.number();
         }
 
@@ -43,7 +43,10 @@ impl<'a> Scanner<'a> {
             Some(")") => self.make_token(TokenKind::RightParen),
             Some("{") => self.make_token(TokenKind::LeftBrace),
             Some("}") => self.make_token(TokenKind::RightBrace),
+            Some("[") => self.make_token(TokenKind::LeftBracket),
+            Some("]") => self.make_token(TokenKind::RightBracket),
             Some(";") => self.make_token(TokenKind::Semicolon),
+            Some(":") => self.make_token(TokenKind::Colon),
             Some(",") => self.make_token(TokenKind::Comma),
             Some(".") => self.make_token(TokenKind::Dot),
             Some("-") => self.make_token(TokenKind::Minus),
@@ -60,6 +63,8 @@ impl<'a> Scanner<'a> {
             Some("=") => {
                 if self.matches("=") {
                     self.make_token(TokenKind::EqualEqual)
+                } else if self.matches(">") {
+                    self.make_token(TokenKind::EqualGreater)
                 } else {
                     self.make_token(TokenKind::Equal)
                 }
@@ -79,11 +84,8 @@ impl<'a> Scanner<'a> {
                 }
             }
             Some("&") => {
-                if self.matches("&") {
-                    self.make_token(TokenKind::And)
-                } else {
-                    self.make_token(TokenKind::And)
-                }
+                self.matches("&");
+                self.make_token(TokenKind::And)
             }
             Some("\"") => self.string(),
             _ => Token::error("Unexpected token", self.start, self.current, self.line),
@@ -151,10 +153,11 @@ impl<'a> Scanner<'a> {
                     self.line += 1;
                 }
                 Some("/") => {
-                    if self.peek_next() == Some("/") {
-                        while self.peek() != Some("\n") && !self.at_end() {
-                            self.advance();
-                        }
+                    if self.peek_next() != Some("/") {
+                        break;
+                    }
+                    while self.peek() != Some("\n") && !self.at_end() {
+                        self.advance();
                     }
                 }
                 _ => break,
@@ -180,14 +183,14 @@ impl<'a> Scanner<'a> {
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().map_or(false, is_digit) {
+        while self.peek().is_some_and(is_digit) {
             self.advance();
         }
 
-        if self.peek() == Some(".") && self.peek_next().map_or(false, is_digit) {
+        if self.peek() == Some(".") && self.peek_next().is_some_and(is_digit) {
             self.advance();
 
-            while self.peek().map_or(false, is_digit) {
+            while self.peek().is_some_and(is_digit) {
                 self.advance();
             }
         }
@@ -196,57 +199,84 @@ impl<'a> Scanner<'a> {
     }
 
     fn identifier(&mut self) -> Token {
-        while self.peek().map_or(false, is_alpha) || self.peek().map_or(false, is_digit) {
+        while self.peek().is_some_and(is_alpha) || self.peek().is_some_and(is_digit) {
             self.advance();
         }
 
         let identifier = self.identifier_type();
-        return self.make_token(identifier);
+        self.make_token(identifier)
     }
 
     fn identifier_type(&mut self) -> TokenKind {
         let c = self.source.get(self.start..self.start + 1);
         match c {
-            Some("a") => self.check_keyword(2, 2, "nd", TokenKind::And),
-            Some("c") => self.check_keyword(2, 4, "class", TokenKind::Class),
-            Some("e") => self.check_keyword(3, 3, "lse", TokenKind::Else),
-            Some("f") => {
-                if self.current - self.start > 1 {
-                    let c = self.source.get((self.start + 1)..(self.start + 2));
-                    match c {
-                        Some("a") => self.check_keyword(3, 3, "lse", TokenKind::False),
-                        Some("o") => self.check_keyword(1, 1, "r", TokenKind::For),
-                        Some("u") => self.check_keyword(1, 1, "n", TokenKind::Fun),
-                        _ => TokenKind::Identifier,
+            Some("a") => self.check_keyword(1, 2, "nd", TokenKind::And),
+            Some("b") => self.check_keyword(1, 4, "reak", TokenKind::Break),
+            Some("c") if self.current - self.start > 1 => {
+                let c = self.source.get((self.start + 1)..(self.start + 2));
+                match c {
+                    Some("a") if self.current - self.start > 2 => {
+                        let c = self.source.get((self.start + 2)..(self.start + 3));
+                        match c {
+                            Some("s") => self.check_keyword(3, 1, "e", TokenKind::Case),
+                            Some("t") => self.check_keyword(3, 2, "ch", TokenKind::Catch),
+                            _ => TokenKind::Identifier,
+                        }
                     }
-                } else {
-                    TokenKind::Error("Unexpected character".to_string())
+                    Some("l") => self.check_keyword(2, 3, "ass", TokenKind::Class),
+                    Some("o") => self.check_keyword(2, 6, "ntinue", TokenKind::Continue),
+                    _ => TokenKind::Identifier,
+                }
+            }
+            Some("d") => self.check_keyword(1, 6, "efault", TokenKind::Default),
+            Some("e") => self.check_keyword(1, 3, "lse", TokenKind::Else),
+            Some("f") if self.current - self.start > 1 => {
+                let c = self.source.get((self.start + 1)..(self.start + 2));
+                match c {
+                    Some("a") => self.check_keyword(2, 3, "lse", TokenKind::False),
+                    Some("i") => self.check_keyword(2, 5, "nally", TokenKind::Finally),
+                    Some("o") => self.check_keyword(2, 1, "r", TokenKind::For),
+                    Some("u") => self.check_keyword(2, 1, "n", TokenKind::Fun),
+                    _ => TokenKind::Identifier,
                 }
             }
             Some("i") => self.check_keyword(1, 1, "f", TokenKind::If),
-            Some("n") => self.check_keyword(2, 2, "il", TokenKind::Nil),
-            Some("o") => self.check_keyword(2, 1, "o", TokenKind::Or),
-            Some("p") => self.check_keyword(4, 4, "rint", TokenKind::Print),
-            Some("r") => self.check_keyword(5, 5, "eturn", TokenKind::Return),
-            Some("s") => self.check_keyword(4, 4, "uper", TokenKind::Super),
-            Some("t") => {
-                if self.current - self.start > 1 {
-                    let c = self.source.get((self.start + 1)..(self.start + 2));
-                    match c {
-                        Some("h") => self.check_keyword(2, 2, "is", TokenKind::This),
-                        Some("r") => self.check_keyword(2, 2, "ue", TokenKind::True),
-                        _ => TokenKind::Identifier,
+            Some("m") => self.check_keyword(1, 4, "atch", TokenKind::Match),
+            Some("n") => self.check_keyword(1, 2, "il", TokenKind::Nil),
+            Some("o") => self.check_keyword(1, 1, "r", TokenKind::Or),
+            Some("p") => self.check_keyword(1, 4, "rint", TokenKind::Print),
+            Some("r") => self.check_keyword(1, 5, "eturn", TokenKind::Return),
+            Some("s") => self.check_keyword(1, 4, "uper", TokenKind::Super),
+            Some("t") if self.current - self.start > 1 => {
+                let c = self.source.get((self.start + 1)..(self.start + 2));
+                match c {
+                    Some("h") if self.current - self.start > 2 => {
+                        let c = self.source.get((self.start + 2)..(self.start + 3));
+                        match c {
+                            Some("i") => self.check_keyword(3, 1, "s", TokenKind::This),
+                            Some("r") => self.check_keyword(3, 2, "ow", TokenKind::Throw),
+                            _ => TokenKind::Identifier,
+                        }
                     }
-                } else {
-                    TokenKind::Error("Unexpected character".to_string())
+                    Some("r") if self.current - self.start > 2 => {
+                        let c = self.source.get((self.start + 2)..(self.start + 3));
+                        match c {
+                            Some("u") => self.check_keyword(3, 1, "e", TokenKind::True),
+                            Some("y") => self.check_keyword(3, 0, "", TokenKind::Try),
+                            _ => TokenKind::Identifier,
+                        }
+                    }
+                    _ => TokenKind::Identifier,
                 }
             }
-            Some("v") => self.check_keyword(2, 2, "ar", TokenKind::Var),
-            Some("w") => self.check_keyword(4, 4, "hile", TokenKind::While),
+            Some("v") => self.check_keyword(1, 2, "ar", TokenKind::Var),
+            Some("w") => self.check_keyword(1, 4, "hile", TokenKind::While),
             _ => TokenKind::Identifier,
         }
     }
 
+    /// Matches the lexeme against a keyword whose first `offset` characters
+    /// have already been checked.
     fn check_keyword(
         &mut self,
         offset: usize,
@@ -254,10 +284,10 @@ impl<'a> Scanner<'a> {
         rest: &str,
         kind: TokenKind,
     ) -> TokenKind {
-        let start = self.current - offset;
-        let range = start..(start + length);
-        let source = self.source.get(range);
-        if source == Some(rest) {
+        let start = self.start + offset;
+        if self.current - self.start == offset + length
+            && self.source.get(start..(start + length)) == Some(rest)
+        {
             return kind;
         }
         TokenKind::Identifier
@@ -265,9 +295,77 @@ impl<'a> Scanner<'a> {
 }
 
 fn is_digit(c: &str) -> bool {
-    c >= "0" && c <= "9"
+    ("0"..="9").contains(&c)
 }
 
 fn is_alpha(c: &str) -> bool {
-    (c >= "a" && c <= "z") || (c >= "A" && c <= "Z") || (c == "_")
+    ("a"..="z").contains(&c) || ("A"..="Z").contains(&c) || (c == "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        let mut scanner = Scanner::new(source);
+        let mut kinds = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            if token.kind == TokenKind::Eof {
+                return kinds;
+            }
+            kinds.push(token.kind);
+        }
+    }
+
+    #[test]
+    fn keywords_must_match_exactly() {
+        assert_eq!(
+            kinds("f t o a fun funny fn or ore thisx this c cont continue class th tr try catch ca throw"),
+            vec![
+                TokenKind::Identifier,
+                TokenKind::Identifier,
+                TokenKind::Identifier,
+                TokenKind::Identifier,
+                TokenKind::Fun,
+                TokenKind::Identifier,
+                TokenKind::Identifier,
+                TokenKind::Or,
+                TokenKind::Identifier,
+                TokenKind::Identifier,
+                TokenKind::This,
+                TokenKind::Identifier,
+                TokenKind::Identifier,
+                TokenKind::Continue,
+                TokenKind::Class,
+                TokenKind::Identifier,
+                TokenKind::Identifier,
+                TokenKind::Try,
+                TokenKind::Catch,
+                TokenKind::Identifier,
+                TokenKind::Throw,
+            ]
+        );
+    }
+
+    #[test]
+    fn equal_greater_is_one_token() {
+        assert_eq!(
+            kinds("= => == >="),
+            vec![
+                TokenKind::Equal,
+                TokenKind::EqualGreater,
+                TokenKind::EqualEqual,
+                TokenKind::GreaterEqual,
+            ]
+        );
+    }
+
+    #[test]
+    fn slash_is_division_unless_doubled() {
+        assert_eq!(
+            kinds("a / b // c\n/"),
+            vec![TokenKind::Identifier, TokenKind::Slash, TokenKind::Identifier, TokenKind::Slash]
+        );
+    }
 }
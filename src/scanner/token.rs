@@ -40,6 +40,9 @@ pub enum TokenKind {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -53,6 +56,7 @@ pub enum TokenKind {
     BangEqual,
     Equal,
     EqualEqual,
+    EqualGreater,
     Greater,
     GreaterEqual,
     Less,
@@ -65,19 +69,28 @@ pub enum TokenKind {
 
     // Keywords.
     And,
+    Break,
+    Case,
+    Catch,
     Class,
+    Continue,
+    Default,
     Else,
     False,
+    Finally,
     Fun,
     For,
     If,
+    Match,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -1,10 +1,16 @@
-use crate::vm::OpCode;
-use crate::core::Value;
+use std::io;
+
+use crate::vm::{Heap, OpCode, UPVALUE_SIZE};
+use crate::core::{Span, Value};
 
 #[derive(Debug, Clone)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
-    pub lines: Vec<u32>,
+    pub code: Vec<u8>,
+    /// Run length encoded, each entry is a line and how many bytes of code
+    /// in a row came from it.
+    pub lines: Vec<(u32, usize)>,
+    /// The offset each instruction starts at along with its span.
+    pub spans: Vec<(usize, Span)>,
     pub constants: Vec<Value>,
 }
 
@@ -14,12 +20,61 @@ impl Chunk {
             code: Vec::new(),
             constants: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    pub fn write_chunk(&mut self, op_code: OpCode, line_number: u32, span: Span) {
+        let start = self.code.len();
+        self.spans.push((start, span));
+        op_code.encode(&mut self.code);
+        self.add_line(line_number, self.code.len() - start);
+    }
+
+    /// Writes the upvalue descriptors that trail a `Closure`.
+    pub fn write_upvalues(&mut self, upvalues: &[(bool, usize)], line_number: u32) {
+        let start = self.code.len();
+        OpCode::encode_upvalues(&mut self.code, upvalues);
+        self.add_line(line_number, self.code.len() - start);
+    }
+
+    fn add_line(&mut self, line_number: u32, count: usize) {
+        match self.lines.last_mut() {
+            Some((line, run)) if *line == line_number => *run += count,
+            _ => self.lines.push((line_number, count)),
+        }
+    }
+
+    /// The line of the code byte at `offset`.
+    pub fn line(&self, offset: usize) -> u32 {
+        let mut end = 0;
+        for (line, count) in &self.lines {
+            end += count;
+            if offset < end {
+                return *line;
+            }
         }
+        0
     }
 
-    pub fn write_chunk(&mut self, op_code: OpCode, line_number: u32) {
-        self.lines.push(line_number);
-        self.code.push(op_code);
+    /// The span of the instruction the byte at `offset` belongs to. Chunks
+    /// loaded from bytecode have no spans.
+    pub fn span(&self, offset: usize) -> Span {
+        match self.spans.partition_point(|(start, _)| *start <= offset) {
+            0 => Span::default(),
+            i => self.spans[i - 1].1,
+        }
+    }
+
+    /// The `(is_local, index)` descriptors trailing the `Closure` at `offset`.
+    pub fn upvalues(&self, offset: usize) -> impl Iterator<Item = (bool, usize)> + '_ {
+        let (start, count) = OpCode::upvalue_descriptors(&self.code, offset).unwrap_or_default();
+        self.code[start..start + UPVALUE_SIZE * count]
+            .chunks(UPVALUE_SIZE)
+            .map(|descriptor| {
+                let index = (descriptor[1] as usize) << 8 | descriptor[2] as usize;
+                (descriptor[0] == 1, index)
+            })
     }
 
     pub fn add_constant(&mut self, constant: Value) -> usize {
@@ -27,10 +82,38 @@ impl Chunk {
         self.constants.len() - 1
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("==== {} ====", name);
-        for (offset, op_code) in self.code.iter().enumerate() {
-            OpCode::disassemble_instruction(op_code, self, offset);
+    pub fn disassemble(&self, name: &str, heap: &Heap) -> String {
+        let mut out = Vec::new();
+        self.write_disassembly(name, heap, &mut out).expect("Writing to a Vec can't fail");
+        String::from_utf8(out).expect("Listings are UTF-8")
+    }
+
+    pub fn write_disassembly<W: io::Write>(&self, name: &str, heap: &Heap, out: &mut W) -> io::Result<()> {
+        writeln!(out, "==== {} ====", name)?;
+        let mut offset = 0;
+        while let Some((op_code, next)) = OpCode::decode(&self.code, offset) {
+            writeln!(out, "{}", OpCode::disassemble_instruction(&op_code, self, offset, heap))?;
+            offset = next;
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_length_encodes_lines() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Constant(0), 1, Span::new(0, 1));
+        chunk.write_chunk(OpCode::Negate, 1, Span::new(2, 1));
+        chunk.write_chunk(OpCode::Return, 2, Span::new(4, 1));
+        assert_eq!(chunk.lines, vec![(1, 3), (2, 1)]);
+        assert_eq!(chunk.line(1), 1);
+        assert_eq!(chunk.line(3), 2);
+        // Operand bytes share the span of their instruction
+        assert_eq!(chunk.span(1), Span::new(0, 1));
+        assert_eq!(chunk.span(2), Span::new(2, 1));
     }
 }
@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+
+use crate::core::{Object, Value};
+use crate::vm::{ObjectId, Vm};
+
+/// Number of live objects the heap may hold before the first collection.
+pub const GC_INITIAL_THRESHOLD: usize = 1024;
+/// How far the threshold grows relative to the survivors of a collection.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Collects the set of reachable heap objects. Roots go on the gray list and
+/// are blackened one at a time until nothing new is reachable.
+struct Marker {
+    marked: HashSet<ObjectId>,
+    gray: Vec<ObjectId>,
+}
+
+impl Marker {
+    fn new() -> Self {
+        Marker {
+            marked: HashSet::new(),
+            gray: Vec::new(),
+        }
+    }
+
+    fn mark_id(&mut self, key: ObjectId) {
+        if self.marked.insert(key) {
+            self.gray.push(key);
+        }
+    }
+
+    fn mark_value(&mut self, value: &Value) {
+        if let Value::Object(key) = value {
+            self.mark_id(*key);
+        }
+    }
+
+    /// Marks everything an object references.
+    fn mark_object(&mut self, object: &Object) {
+        match object {
+            Object::String(_) | Object::NativeFunction(_) => (),
+            Object::Function(function) => {
+                for constant in &function.chunk.constants {
+                    self.mark_value(constant);
+                }
+            }
+            Object::Closure(closure) => {
+                self.mark_id(closure.function);
+                for upvalue in &closure.upvalues {
+                    self.mark_id(*upvalue);
+                }
+            }
+            Object::Upvalue(upvalue) => {
+                if let Some(value) = &upvalue.closed {
+                    self.mark_value(value);
+                }
+            }
+            Object::Class(class) => {
+                for method in class.methods.values() {
+                    self.mark_id(*method);
+                }
+                if let Some(superclass) = class.superclass {
+                    self.mark_id(superclass);
+                }
+            }
+            Object::Instance(instance) => {
+                self.mark_id(instance.class);
+                for value in instance.fields.values() {
+                    self.mark_value(value);
+                }
+            }
+            Object::BoundMethod(bound) => {
+                self.mark_id(bound.receiver);
+                self.mark_id(bound.method);
+            }
+            Object::List(items) => {
+                for item in items {
+                    self.mark_value(item);
+                }
+            }
+            Object::Map(map) => {
+                for (key, value) in map.entries() {
+                    self.mark_value(key);
+                    self.mark_value(value);
+                }
+            }
+        }
+    }
+}
+
+impl Vm {
+    pub(super) fn should_collect(&self) -> bool {
+        self.heap.len() > self.next_gc
+    }
+
+    /// Marks every object reachable from the VM roots and frees the rest.
+    pub(super) fn collect_garbage(&mut self) {
+        let before = self.heap.len();
+        if cfg!(feature = "gc") {
+            eprintln!("-- gc begin ({} objects)", before);
+        }
+
+        let mut marker = Marker::new();
+        for slot in 0..self.stack.top() {
+            marker.mark_value(&self.stack[slot]);
+        }
+        for frame in &self.frames {
+            marker.mark_id(frame.closure);
+        }
+        for (name, value) in &self.globals {
+            marker.mark_id(*name);
+            marker.mark_value(value);
+        }
+        for upvalue in &self.open_upvalues {
+            marker.mark_id(*upvalue);
+        }
+        for arg in self.heap.args() {
+            marker.mark_id(*arg);
+        }
+        marker.mark_id(self.error_class);
+
+        while let Some(key) = marker.gray.pop() {
+            if let Some(object) = self.heap.get(&key) {
+                marker.mark_object(object);
+            }
+        }
+
+        self.heap.retain(|key| marker.marked.contains(&key));
+        self.next_gc = (self.heap.len() * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+
+        if cfg!(feature = "gc") {
+            eprintln!(
+                "-- gc end: collected {} objects ({} -> {}), next at {}",
+                before - self.heap.len(),
+                before,
+                self.heap.len(),
+                self.next_gc
+            );
+        }
+    }
+}
@@ -1,31 +1,225 @@
-use slotmap::{DefaultKey, SlotMap};
-use crate::core::Object;
+use std::collections::HashMap;
 
-pub type ObjectId = DefaultKey;
+use slotmap::SlotMap;
+use crate::core::{Function, Map, MapKey, Object, RuntimeError, Value};
 
+slotmap::new_key_type! {
+    /// A handle to an object on a VM's heap. It only means something to the
+    /// heap it came from, and only while the object is alive.
+    pub struct ObjectId;
+}
+
+/// Owns every object the compiler and the VM allocate. Values only ever hold
+/// an `ObjectId` into this map.
+///
+/// Strings are interned: each distinct string is allocated once, so two
+/// string values are equal exactly when their ids are.
+///
+/// Native functions are handed the heap, but outside the crate they can only
+/// intern strings, read strings, lists and maps and allocate new lists and
+/// maps. Collection and changes to existing objects are left to the VM.
 pub struct Heap {
     data: SlotMap<ObjectId, Object>,
-    object_id_counter: usize
+    strings: HashMap<String, ObjectId>,
+    /// The command line arguments handed to the script, as string objects.
+    args: Vec<ObjectId>,
 }
 
 impl Heap {
-    pub fn new() -> Self {
-        let data = SlotMap::new();
-        let object_id_counter = 1;
-        Heap { data, object_id_counter }
+    pub(crate) fn new() -> Self {
+        let data = SlotMap::with_key();
+        let strings = HashMap::new();
+        Heap {
+            data,
+            strings,
+            args: Vec::new(),
+        }
+    }
+
+    /// Returns the id of the string object holding `s`, allocating it the
+    /// first time it is seen.
+    pub fn intern(&mut self, s: &str) -> ObjectId {
+        match self.strings.get(s) {
+            Some(id) => *id,
+            None => self.intern_owned(String::from(s)),
+        }
     }
 
-    pub fn add_value(&mut self, value: Object) -> ObjectId {
-        let id = self.data.insert(value);
-        self.object_id_counter += 1;
+    pub(crate) fn intern_owned(&mut self, s: String) -> ObjectId {
+        if let Some(id) = self.strings.get(&s) {
+            return *id;
+        }
+        let id = self.data.insert(Object::String(s.clone()));
+        self.strings.insert(s, id);
         id
     }
 
-    pub fn get(&self, object_id: &ObjectId) -> Option<&Object> {
+    pub(crate) fn add_value(&mut self, value: Object) -> ObjectId {
+        self.data.insert(value)
+    }
+
+    /// Allocates a list holding `items`.
+    pub fn new_list(&mut self, items: Vec<Value>) -> ObjectId {
+        self.add_value(Object::List(items))
+    }
+
+    /// Allocates a map of `entries`. Later keys win, as in a map literal.
+    pub fn new_map(&mut self, entries: &[(Value, Value)]) -> Result<ObjectId, RuntimeError> {
+        let mut map = Map::new();
+        for (key, value) in entries {
+            map.insert(MapKey::new(key, self)?, *key, *value);
+        }
+        Ok(self.add_value(Object::Map(map)))
+    }
+
+    pub(crate) fn get(&self, object_id: &ObjectId) -> Option<&Object> {
         self.data.get(*object_id)
     }
 
-    pub fn get_mut(&mut self, object_id: &ObjectId) -> Option<&mut Object> {
+    pub(crate) fn get_mut(&mut self, object_id: &ObjectId) -> Option<&mut Object> {
         self.data.get_mut(*object_id)
     }
-}
\ No newline at end of file
+
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Frees every object `keep` rejects. The intern table holds its strings
+    /// weakly, so freed strings are forgotten as well.
+    pub(crate) fn retain<F: FnMut(ObjectId) -> bool>(&mut self, mut keep: F) {
+        self.data.retain(|key, _| keep(key));
+        let data = &self.data;
+        self.strings.retain(|_, id| data.contains_key(*id));
+    }
+
+    pub(crate) fn set_args(&mut self, args: &[String]) {
+        self.args = args.iter().map(|arg| self.intern(arg)).collect();
+    }
+
+    pub(crate) fn args(&self) -> &[ObjectId] {
+        &self.args
+    }
+
+    pub fn get_string(&self, object_id: &ObjectId) -> Option<&String> {
+        match self.get(object_id) {
+            Some(Object::String(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_function(&self, object_id: &ObjectId) -> Option<&Function> {
+        match self.get(object_id) {
+            Some(Object::Function(function)) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn get_list(&self, object_id: &ObjectId) -> Option<&Vec<Value>> {
+        match self.get(object_id) {
+            Some(Object::List(items)) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_list_mut(&mut self, object_id: &ObjectId) -> Option<&mut Vec<Value>> {
+        match self.get_mut(object_id) {
+            Some(Object::List(items)) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn get_map(&self, object_id: &ObjectId) -> Option<&Map> {
+        match self.get(object_id) {
+            Some(Object::Map(map)) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_map_mut(&mut self, object_id: &ObjectId) -> Option<&mut Map> {
+        match self.get_mut(object_id) {
+            Some(Object::Map(map)) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn format_value(&self, value: &Value) -> String {
+        self.format_nested(value, &mut Vec::new())
+    }
+
+    /// `enclosing` holds the lists and maps being printed around `value`, so
+    /// one that contains itself prints as `[...]` or `{...}` rather than
+    /// forever.
+    fn format_nested(&self, value: &Value, enclosing: &mut Vec<ObjectId>) -> String {
+        match value {
+            Value::Object(id) => match self.get(id) {
+                Some(Object::List(_)) if enclosing.contains(id) => String::from("[...]"),
+                Some(Object::List(items)) => {
+                    enclosing.push(*id);
+                    let items: Vec<String> =
+                        items.iter().map(|item| self.format_nested(item, enclosing)).collect();
+                    enclosing.pop();
+                    format!("[{}]", items.join(", "))
+                }
+                Some(Object::Map(_)) if enclosing.contains(id) => String::from("{...}"),
+                Some(Object::Map(map)) => {
+                    enclosing.push(*id);
+                    let entries: Vec<String> = map
+                        .entries()
+                        .iter()
+                        .map(|(key, value)| {
+                            let key = self.format_nested(key, enclosing);
+                            format!("{}: {}", key, self.format_nested(value, enclosing))
+                        })
+                        .collect();
+                    enclosing.pop();
+                    format!("{{{}}}", entries.join(", "))
+                }
+                Some(object) => self.format_object(object),
+                None => value.to_string(),
+            },
+            _ => value.to_string(),
+        }
+    }
+
+    fn format_object(&self, object: &Object) -> String {
+        match object {
+            Object::String(s) => s.clone(),
+            Object::Function(function) => function.to_string(),
+            Object::NativeFunction(function) => function.to_string(),
+            Object::Closure(closure) => self.format_value(&Value::Object(closure.function)),
+            Object::Upvalue(upvalue) => upvalue.to_string(),
+            Object::Class(class) => class.to_string(),
+            Object::Instance(instance) => match self.get(&instance.class) {
+                Some(Object::Class(class)) => format!("{} instance", class.name),
+                _ => instance.to_string(),
+            },
+            Object::BoundMethod(bound) => self.format_value(&Value::Object(bound.method)),
+            Object::List(_) | Object::Map(_) => unreachable!("Collections are formatted by format_nested"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_equal_strings_once() {
+        let mut heap = Heap::new();
+        let a = heap.intern("hello");
+        let b = heap.intern_owned(format!("{}{}", "hel", "lo"));
+        assert_eq!(a, b);
+        assert_ne!(a, heap.intern("world"));
+        assert_eq!(heap.len(), 2);
+    }
+
+    #[test]
+    fn forgets_collected_strings() {
+        let mut heap = Heap::new();
+        let a = heap.intern("hello");
+        heap.retain(|_| false);
+        let b = heap.intern("hello");
+        assert_ne!(a, b);
+        assert_eq!(heap.get_string(&b).map(String::as_str), Some("hello"));
+    }
+}
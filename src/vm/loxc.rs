@@ -0,0 +1,563 @@
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::core::{Function, FunctionType, Object, Value};
+use crate::vm::{Heap, ObjectId, OpCode, UPVALUE_SIZE};
+
+/// Every `.loxc` file starts with these bytes.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the layout below changes. Files from another version are
+/// refused rather than misread.
+pub const VERSION: u16 = 8;
+
+const HEADER_LEN: usize = 10;
+
+/// Why a `.loxc` file could not be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    /// The file doesn't start with `MAGIC`.
+    NotBytecode,
+    /// The file was written for a different format version.
+    UnsupportedVersion(u16),
+    /// The payload doesn't match its checksum.
+    ChecksumMismatch,
+    /// The payload ended in the middle of something.
+    Truncated,
+    /// The payload decoded to something the VM can't run.
+    Malformed(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a compiled Lox file."),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "Compiled for bytecode version {} but this VM reads version {}.",
+                version, VERSION
+            ),
+            LoadError::ChecksumMismatch => write!(f, "Checksum mismatch, the file is corrupt."),
+            LoadError::Truncated => write!(f, "Unexpected end of file."),
+            LoadError::Malformed(message) => write!(f, "Malformed bytecode: {}", message),
+        }
+    }
+}
+
+/// Serializes the script `function` and every function nested in it.
+///
+/// The layout is the magic, a little endian `u16` version and a CRC-32 of
+/// the payload, followed by the payload itself: the script function, with
+/// nested functions written inline where they appear as constants.
+pub fn save(function: ObjectId, heap: &Heap) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_function(&mut payload, heap.get_function(&function).unwrap(), heap);
+
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&crc32(&payload).to_le_bytes());
+    out.extend_from_slice(&payload);
+    out
+}
+
+/// Checks the header and allocates the functions in `bytes` on the heap,
+/// returning the script.
+pub fn load(bytes: &[u8], heap: &mut Heap) -> Result<ObjectId, LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(LoadError::Truncated);
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let checksum = u32::from_le_bytes(bytes[6..10].try_into().unwrap());
+    let payload = &bytes[HEADER_LEN..];
+    if crc32(payload) != checksum {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader { bytes: payload, position: 0 };
+    let function = reader.function(heap)?;
+    if reader.position != payload.len() {
+        return Err(LoadError::Malformed(String::from("trailing bytes after the script")));
+    }
+    match function.function_type() {
+        // The VM runs the script with no arguments and no enclosing closure
+        FunctionType::Script if function.arity > 0 || function.upvalue_count > 0 => Err(LoadError::Malformed(
+            String::from("the script can't take arguments or capture upvalues"),
+        )),
+        FunctionType::Script => Ok(heap.add_value(Object::Function(function))),
+        _ => Err(LoadError::Malformed(String::from("the outermost function isn't a script"))),
+    }
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+const CONSTANT_NIL: u8 = 0;
+const CONSTANT_TRUE: u8 = 1;
+const CONSTANT_FALSE: u8 = 2;
+const CONSTANT_NUMBER: u8 = 3;
+const CONSTANT_STRING: u8 = 4;
+const CONSTANT_FUNCTION: u8 = 5;
+
+fn write_function(out: &mut Vec<u8>, function: &Function, heap: &Heap) {
+    write_str(out, function.name());
+    out.push(match function.function_type() {
+        FunctionType::Script => 0,
+        FunctionType::Function => 1,
+        FunctionType::Method => 2,
+        FunctionType::Initializer => 3,
+        FunctionType::Native => unreachable!("Natives are never compiled"),
+    });
+    write_u32(out, function.arity);
+    write_u32(out, function.upvalue_count);
+
+    let chunk = &function.chunk;
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+    write_u32(out, chunk.lines.len());
+    for (line, count) in &chunk.lines {
+        out.extend_from_slice(&line.to_le_bytes());
+        write_u32(out, *count);
+    }
+
+    write_u32(out, chunk.constants.len());
+    for constant in &chunk.constants {
+        match constant {
+            Value::Nil => out.push(CONSTANT_NIL),
+            Value::Bool(true) => out.push(CONSTANT_TRUE),
+            Value::Bool(false) => out.push(CONSTANT_FALSE),
+            Value::Number(n) => {
+                out.push(CONSTANT_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Object(id) => match heap.get(id) {
+                Some(Object::String(s)) => {
+                    out.push(CONSTANT_STRING);
+                    write_str(out, s);
+                }
+                Some(Object::Function(function)) => {
+                    out.push(CONSTANT_FUNCTION);
+                    write_function(out, function, heap);
+                }
+                _ => unreachable!("The compiler only makes string and function constants"),
+            },
+        }
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.position.checked_add(count).ok_or(LoadError::Truncated)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(LoadError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A count of items that are each at least one byte long, checked
+    /// against what's left so a corrupt length can't exhaust memory.
+    fn count(&mut self) -> Result<usize, LoadError> {
+        let count = self.u32()? as usize;
+        if count > self.bytes.len() - self.position {
+            return Err(LoadError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let length = self.count()?;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| LoadError::Malformed(String::from("a string isn't valid UTF-8")))
+    }
+
+    fn function(&mut self, heap: &mut Heap) -> Result<Function, LoadError> {
+        let name = self.string()?;
+        let function_type = match self.u8()? {
+            0 => FunctionType::Script,
+            1 => FunctionType::Function,
+            2 => FunctionType::Method,
+            3 => FunctionType::Initializer,
+            tag => return Err(LoadError::Malformed(format!("unknown function type {}", tag))),
+        };
+        let mut function = Function::new(&name, function_type);
+        function.arity = self.u32()? as usize;
+        function.upvalue_count = self.u32()? as usize;
+
+        let code_len = self.count()?;
+        let code = self.take(code_len)?.to_vec();
+        let run_count = self.count()?;
+        let mut lines = Vec::with_capacity(run_count);
+        for _ in 0..run_count {
+            lines.push((self.u32()?, self.u32()? as usize));
+        }
+
+        let constant_count = self.count()?;
+        let mut constants = Vec::with_capacity(constant_count);
+        for _ in 0..constant_count {
+            let constant = match self.u8()? {
+                CONSTANT_NIL => Value::Nil,
+                CONSTANT_TRUE => Value::Bool(true),
+                CONSTANT_FALSE => Value::Bool(false),
+                CONSTANT_NUMBER => {
+                    let bits = self.take(8)?.try_into().unwrap();
+                    Value::from(f64::from_le_bytes(bits))
+                }
+                CONSTANT_STRING => {
+                    let s = self.string()?;
+                    Value::Object(heap.intern_owned(s))
+                }
+                CONSTANT_FUNCTION => {
+                    let nested = self.function(heap)?;
+                    Value::Object(heap.add_value(Object::Function(nested)))
+                }
+                tag => return Err(LoadError::Malformed(format!("unknown constant tag {}", tag))),
+            };
+            constants.push(constant);
+        }
+
+        function.chunk.code = code;
+        function.chunk.lines = lines;
+        function.chunk.constants = constants;
+        validate(&function, heap)?;
+        Ok(function)
+    }
+}
+
+/// Rejects code the VM can't decode, operands it would index out of bounds
+/// with and instructions that would reach below the frame's stack.
+fn validate(function: &Function, heap: &Heap) -> Result<(), LoadError> {
+    let chunk = &function.chunk;
+    let malformed = |offset: usize| {
+        LoadError::Malformed(format!("bad instruction at {} in {}", offset, function.name()))
+    };
+    if chunk.lines.iter().map(|(_, count)| count).sum::<usize>() != chunk.code.len() {
+        return Err(LoadError::Malformed(format!("{} has the wrong line count", function.name())));
+    }
+    let is_string = |index: usize| match chunk.constants.get(index) {
+        Some(Value::Object(id)) => heap.get_string(id).is_some(),
+        _ => false,
+    };
+
+    // Decode the whole chunk first so jumps can be checked to land on an
+    // instruction
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (op_code, next) = OpCode::decode(&chunk.code, offset).ok_or_else(|| malformed(offset))?;
+        instructions.push((offset, op_code, next));
+        offset = next;
+    }
+    if !matches!(instructions.last(), Some((_, OpCode::Return, _))) {
+        return Err(LoadError::Malformed(format!("{} doesn't end in a return", function.name())));
+    }
+    let lands = |target: usize| instructions.binary_search_by_key(&target, |(offset, _, _)| *offset).is_ok();
+
+    for &(offset, op_code, next) in &instructions {
+        let valid = match op_code {
+            OpCode::Constant(index) | OpCode::ConstantLong(index) => index < chunk.constants.len(),
+            OpCode::DefineGlobal(index)
+            | OpCode::GetGlobal(index)
+            | OpCode::SetGlobal(index)
+            | OpCode::Class(index)
+            | OpCode::GetProperty(index)
+            | OpCode::SetProperty(index)
+            | OpCode::Method(index)
+            | OpCode::GetSuper(index)
+            | OpCode::DefineGlobalLong(index)
+            | OpCode::GetGlobalLong(index)
+            | OpCode::SetGlobalLong(index)
+            | OpCode::ClassLong(index)
+            | OpCode::GetPropertyLong(index)
+            | OpCode::SetPropertyLong(index)
+            | OpCode::MethodLong(index)
+            | OpCode::GetSuperLong(index) => is_string(index),
+            // The closure must describe each of the function's upvalues, and
+            // can only capture upvalues this function has
+            OpCode::Closure(index) | OpCode::ClosureLong(index) => match chunk.constants.get(index) {
+                Some(Value::Object(id)) => heap.get_function(id).is_some_and(|closed| {
+                    let (start, count) = OpCode::upvalue_descriptors(&chunk.code, offset).unwrap_or_default();
+                    count == closed.upvalue_count
+                        && chunk.code[start..next].iter().step_by(UPVALUE_SIZE).all(|local| *local <= 1)
+                        && chunk.upvalues(offset).all(|(is_local, index)| is_local || index < function.upvalue_count)
+                }),
+                _ => false,
+            },
+            OpCode::GetUpvalue(index)
+            | OpCode::SetUpvalue(index)
+            | OpCode::GetUpvalueLong(index)
+            | OpCode::SetUpvalueLong(index) => index < function.upvalue_count,
+            OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) | OpCode::Try(jump) => lands(next + jump),
+            OpCode::Loop(jump) => jump <= next && lands(next - jump),
+            _ => true,
+        };
+        if !valid {
+            return Err(malformed(offset));
+        }
+    }
+    check_stack(function, &instructions).map_err(malformed)
+}
+
+/// Follows every path through the decoded `instructions` counting the
+/// values on the frame's stack, the callee and its arguments to begin with.
+/// Every path into an instruction has to agree on the count, and nothing may
+/// pop or read a local past it. Returns the offset of the first that does.
+fn check_stack(function: &Function, instructions: &[(usize, OpCode, usize)]) -> Result<(), usize> {
+    let index_of = |offset: usize| instructions.binary_search_by_key(&offset, |(offset, _, _)| *offset).ok();
+    let mut depths = vec![None; instructions.len()];
+    let mut pending = vec![(0, function.arity + 1)];
+    while let Some((index, depth)) = pending.pop() {
+        let (offset, op_code, next) = instructions[index];
+        match depths[index] {
+            Some(seen) if seen == depth => continue,
+            Some(_) => return Err(offset),
+            None => depths[index] = Some(depth),
+        }
+
+        let (pops, pushes) = match op_code {
+            OpCode::Return | OpCode::Throw | OpCode::Rethrow => (1, 0),
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Constant(_)
+            | OpCode::ConstantLong(_)
+            | OpCode::GetGlobal(_)
+            | OpCode::GetGlobalLong(_)
+            | OpCode::GetUpvalue(_)
+            | OpCode::GetUpvalueLong(_)
+            | OpCode::Class(_)
+            | OpCode::ClassLong(_) => (0, 1),
+            OpCode::GetLocal(slot) | OpCode::GetLocalLong(slot) if slot < depth => (0, 1),
+            OpCode::SetLocal(slot) | OpCode::SetLocalLong(slot) if slot < depth => (1, 1),
+            OpCode::GetLocal(_) | OpCode::GetLocalLong(_) | OpCode::SetLocal(_) | OpCode::SetLocalLong(_) => {
+                return Err(offset)
+            }
+            // Captured locals have to be on the stack already
+            OpCode::Closure(_) | OpCode::ClosureLong(_) => {
+                let code = &function.chunk;
+                if code.upvalues(offset).any(|(is_local, slot)| is_local && slot >= depth) {
+                    return Err(offset);
+                }
+                (0, 1)
+            }
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::SetGlobal(_)
+            | OpCode::SetGlobalLong(_)
+            | OpCode::SetUpvalue(_)
+            | OpCode::SetUpvalueLong(_)
+            | OpCode::GetProperty(_)
+            | OpCode::GetPropertyLong(_)
+            | OpCode::JumpIfFalse(_) => (1, 1),
+            OpCode::Print | OpCode::Pop | OpCode::DefineGlobal(_) | OpCode::DefineGlobalLong(_) | OpCode::CloseUpvalue => {
+                (1, 0)
+            }
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::SetProperty(_)
+            | OpCode::SetPropertyLong(_)
+            | OpCode::Method(_)
+            | OpCode::MethodLong(_)
+            | OpCode::Inherit
+            | OpCode::GetSuper(_)
+            | OpCode::GetSuperLong(_)
+            | OpCode::GetIndex => (2, 1),
+            OpCode::SetIndex => (3, 1),
+            OpCode::Call(count) | OpCode::CallLong(count) => (count + 1, 1),
+            OpCode::BuildList(count) => (count, 1),
+            OpCode::BuildMap(count) => (2 * count, 1),
+            OpCode::Jump(_) | OpCode::Loop(_) | OpCode::Try(_) | OpCode::PopHandler => (0, 0),
+        };
+        if pops > depth {
+            return Err(offset);
+        }
+        let depth = depth - pops + pushes;
+
+        // Targets were checked to land on an instruction, and the last
+        // instruction is a return, so every `next` here is one too
+        let successors = match op_code {
+            OpCode::Return | OpCode::Throw | OpCode::Rethrow => vec![],
+            OpCode::Jump(jump) => vec![(next + jump, depth)],
+            OpCode::Loop(jump) => vec![(next - jump, depth)],
+            OpCode::JumpIfFalse(jump) => vec![(next, depth), (next + jump, depth)],
+            // A handler cuts the stack back to where it was and pushes what
+            // was thrown
+            OpCode::Try(jump) => vec![(next, depth), (next + jump, depth + 1)],
+            _ => vec![(next, depth)],
+        };
+        for (target, depth) in successors {
+            pending.push((index_of(target).ok_or(offset)?, depth));
+        }
+    }
+    Ok(())
+}
+
+/// CRC-32 as used by zip and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::core::Span;
+
+    fn compiled(source: &str, heap: &mut Heap) -> ObjectId {
+        let function = Function::new("Script", FunctionType::Script);
+        compile(source, function, heap).unwrap()
+    }
+
+    #[test]
+    fn checksum_matches_the_reference() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trips_nested_functions() {
+        let source = "fun outer() { var x = 1.5; fun inner() { return x; } return inner; } print outer()();";
+        let mut heap = Heap::new();
+        let script = compiled(source, &mut heap);
+        let bytes = save(script, &heap);
+
+        let mut loaded_heap = Heap::new();
+        let loaded = load(&bytes, &mut loaded_heap).unwrap();
+        assert_eq!(
+            loaded_heap.get_function(&loaded).unwrap().disassemble(&loaded_heap),
+            heap.get_function(&script).unwrap().disassemble(&heap)
+        );
+        // Saving what was loaded gives back the same file
+        assert_eq!(save(loaded, &loaded_heap), bytes);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let mut heap = Heap::new();
+        let script = compiled("print 1;", &mut heap);
+        let bytes = save(script, &heap);
+
+        assert_eq!(load(b"print 1;", &mut heap), Err(LoadError::NotBytecode));
+
+        let mut version = bytes.clone();
+        version[4] = 99;
+        assert_eq!(load(&version, &mut heap), Err(LoadError::UnsupportedVersion(99)));
+
+        let mut corrupt = bytes.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xFF;
+        assert_eq!(load(&corrupt, &mut heap), Err(LoadError::ChecksumMismatch));
+
+        assert_eq!(load(&bytes[..8], &mut heap), Err(LoadError::Truncated));
+    }
+
+    #[test]
+    fn rejects_upvalues_the_function_does_not_have() {
+        let heap = Heap::new();
+        let mut function = Function::new("f", FunctionType::Function);
+        function.chunk.write_chunk(OpCode::GetUpvalue(0), 1, Span::default());
+        function.chunk.write_chunk(OpCode::Return, 1, Span::default());
+        assert_eq!(
+            validate(&function, &heap),
+            Err(LoadError::Malformed(String::from("bad instruction at 0 in f")))
+        );
+
+        function.upvalue_count = 1;
+        assert_eq!(validate(&function, &heap), Ok(()));
+    }
+
+    #[test]
+    fn rejects_scripts_with_arguments_or_upvalues() {
+        let mut heap = Heap::new();
+        let script = compiled("print 1;", &mut heap);
+        for (arity, upvalue_count) in [(1, 0), (0, 1)] {
+            let mut function = heap.get_function(&script).unwrap().clone();
+            function.arity = arity;
+            function.upvalue_count = upvalue_count;
+            function.chunk.code.clear();
+            function.chunk.lines.clear();
+            function.chunk.write_chunk(OpCode::Nil, 1, Span::default());
+            function.chunk.write_chunk(OpCode::Return, 1, Span::default());
+            let function = heap.add_value(Object::Function(function));
+            assert_eq!(
+                load(&save(function, &heap), &mut Heap::new()),
+                Err(LoadError::Malformed(String::from("the script can't take arguments or capture upvalues")))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_code_reaching_below_the_stack() {
+        let heap = Heap::new();
+        let programs = [
+            vec![OpCode::GetLocal(1), OpCode::Return],
+            vec![OpCode::Pop, OpCode::Pop, OpCode::Nil, OpCode::Return],
+            vec![OpCode::Nil, OpCode::Call(2), OpCode::Return],
+            // The paths meeting at the return disagree on the stack
+            vec![OpCode::Nil, OpCode::JumpIfFalse(1), OpCode::Nil, OpCode::Return],
+        ];
+        for program in programs {
+            let mut function = Function::new("f", FunctionType::Function);
+            for op_code in &program {
+                function.chunk.write_chunk(*op_code, 1, Span::default());
+            }
+            assert!(validate(&function, &heap).is_err(), "{:?}", program);
+        }
+    }
+
+    #[test]
+    fn accepts_everything_the_compiler_writes() {
+        let paths = std::fs::read_dir("test").unwrap().chain(std::fs::read_dir("test/disasm").unwrap());
+        for path in paths.map(|entry| entry.unwrap().path()) {
+            if path.extension().is_none_or(|extension| extension != "lox") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let mut heap = Heap::new();
+            let function = Function::new("Script", FunctionType::Script);
+            if let Ok(script) = compile(&source, function, &mut heap) {
+                let bytes = save(script, &heap);
+                assert!(load(&bytes, &mut Heap::new()).is_ok(), "{}", path.display());
+            }
+        }
+    }
+}
@@ -1,94 +1,274 @@
-use slotmap::{DefaultKey, SlotMap};
-use std::collections::{HashMap, HashSet};
-use std::time::SystemTime;
+use std::collections::HashMap;
 
 mod chunk;
+mod gc;
 mod heap;
+mod loxc;
+mod natives;
 mod op_code;
 mod stack;
+mod trace;
 
-use crate::compiler::compile;
-use crate::core::{Closure, Function, FunctionType, NativeFunction, Object, UpvalueRef, Value};
+use crate::compiler::{compile, CompilerError};
+use crate::core::{
+    Arity, BoundMethod, Class, Closure, Function, FunctionType, Instance, Map, MapKey, NativeFn,
+    NativeFunction, Object, RuntimeError, TraceFrame, UpvalueRef, Value,
+};
 pub use chunk::Chunk;
 pub use heap::{Heap, ObjectId};
-pub use op_code::OpCode;
+pub use loxc::{is_bytecode, LoadError};
+pub use op_code::{OpCode, JUMP_SIZE, UPVALUE_SIZE};
 use stack::Stack;
+pub use trace::{TraceKind, TraceOptions, Tracer, WriteTracer};
 
 const FRAMES_MAX: usize = 64;
 
 macro_rules! bin_op {
-  ( $stack:ident, $op:tt ) => {{
-    let a = $stack.peek(0);
-    let b = $stack.peek(1);
+  ( $vm:expr, $op:tt ) => {
+    bin_op!($vm, $op, "Operands must be numbers.")
+  };
+  ( $vm:expr, $op:tt, $error:expr ) => {{
+    let a = $vm.stack.peek(0);
+    let b = $vm.stack.peek(1);
     if a.is_number() && b.is_number() {
-      let a = $stack.pop();
-      let b = $stack.pop();
+      let a = $vm.stack.pop();
+      let b = $vm.stack.pop();
       match b $op a {
-        Ok(value) => $stack.push(value),
-        Err(msg) => break VmResult::RuntimeError(String::from(msg))
+        Ok(value) => $vm.stack.push(value),
+        Err(msg) => break $vm.runtime_error(String::from(msg))
       }
+    } else {
+      break $vm.runtime_error(String::from($error));
     }
   }};
 }
 
+/// A Lox virtual machine. Globals, the stack and heap objects outlive a
+/// single `interpret`, so a REPL or a host can keep building on earlier code.
 pub struct Vm {
     frames: Vec<CallFrame>,
-    heap: SlotMap<DefaultKey, Object>,
+    stack: Stack,
+    heap: Heap,
+    globals: HashMap<ObjectId, Value>,
+    open_upvalues: Vec<ObjectId>,
+    next_gc: usize,
+    tracer: Option<Box<dyn Tracer>>,
+    /// The class of the values runtime errors are caught as, kept even if
+    /// a script reassigns the global `Error`.
+    error_class: ObjectId,
+    /// The value last caught and how to report it if nothing catches it,
+    /// so a `Rethrow` after a `finally` points back at where it started.
+    /// Taken by that `Rethrow`, so it never outlives the `try`.
+    thrown: Option<(Value, RuntimeError)>,
 }
 
 #[derive(Debug)]
 struct CallFrame {
-    closure: Closure,
+    closure: ObjectId,
+    function: ObjectId,
     ip: usize,
     slots: usize,
+    /// The `try` blocks running in this frame, innermost last.
+    handlers: Vec<Handler>,
 }
 
-impl CallFrame {
-    fn code_at(&self, index: usize) -> &OpCode {
-        &self.closure.function.chunk.code[index]
-    }
-
-    fn chunk(&self) -> &Chunk {
-        &self.closure.function.chunk
-    }
-
-    fn get_constant(&self, index: usize) -> Option<&Value> {
-        self.closure.function.chunk.constants.get(index)
-    }
+/// Where to resume when something is thrown inside a `try`.
+#[derive(Debug)]
+struct Handler {
+    catch: usize,
+    /// The stack height when the `try` began, what unwinding cuts back to.
+    stack_top: usize,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum VmResult {
     Ok,
+    #[allow(dead_code)]
     SyntaxError,
-    CompileError,
-    RuntimeError(String),
+    CompileError(CompilerError),
+    /// Precompiled bytecode could not be loaded.
+    LoadError(LoadError),
+    RuntimeError(RuntimeError),
 }
 
 impl Vm {
     pub fn new() -> Vm {
-        Vm {
+        let mut heap = Heap::new();
+        let error_class = heap.add_value(Object::Class(Class::new("Error")));
+        let mut vm = Vm {
             frames: Vec::new(),
-            heap: SlotMap::new(),
+            stack: Stack::new(),
+            heap,
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            next_gc: gc::GC_INITIAL_THRESHOLD,
+            tracer: None,
+            error_class,
+            thrown: None,
+        };
+        vm.define_global("Error", Value::Object(error_class));
+        for native in natives::standard_library() {
+            vm.define_native(&native.name, native.arity, native.function);
         }
+        vm
     }
 
+    /// Compiles and runs `source`. Top level declarations stay defined
+    /// afterwards, so later calls can use them.
     pub fn interpret(&mut self, source: &str) -> VmResult {
         let function = Function::new("Script", FunctionType::Script);
-        let mut strings: HashSet<String> = HashSet::new();
-        if let Ok(function) = compile(source, function, &mut strings) {
-            let closure = Closure::new(function);
-            self.frames.push(CallFrame {
-                closure,
-                ip: 0,
-                slots: 0,
-            });
-            self.run(&mut strings)
-        } else {
-            return VmResult::CompileError;
+        let function = match compile(source, function, &mut self.heap) {
+            Ok(function) => function,
+            Err(error) => return VmResult::CompileError(error),
+        };
+        self.execute(function)
+    }
+
+    /// Compiles `source` to the `.loxc` format `interpret_bytecode` runs.
+    pub fn compile_bytecode(&mut self, source: &str) -> Result<Vec<u8>, CompilerError> {
+        let function = Function::new("Script", FunctionType::Script);
+        let script = compile(source, function, &mut self.heap)?;
+        Ok(loxc::save(script, &self.heap))
+    }
+
+    /// Loads and runs a script saved by `compile_bytecode`.
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> VmResult {
+        match loxc::load(bytes, &mut self.heap) {
+            Ok(function) => self.execute(function),
+            Err(error) => VmResult::LoadError(error),
+        }
+    }
+
+    /// Lists the bytecode of a script saved by `compile_bytecode`.
+    pub fn disassemble_bytecode(&mut self, bytes: &[u8]) -> Result<String, LoadError> {
+        let script = loxc::load(bytes, &mut self.heap)?;
+        Ok(self.heap.get_function(&script).unwrap().disassemble(&self.heap))
+    }
+
+    /// Runs a compiled script function.
+    fn execute(&mut self, function: ObjectId) -> VmResult {
+        self.trace_code(function);
+
+        let closure = self.allocate(Object::Closure(Closure::new(function)));
+        // The script occupies slot zero just like any other callee
+        let slots = self.stack.top();
+        self.stack.push(Value::Object(closure));
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots,
+            handlers: Vec::new(),
+        });
+        if self.tracing(TraceKind::Calls) {
+            let depth = self.frames.len();
+            self.tracer.as_mut().unwrap().call("script", depth);
+        }
+        let result = self.run();
+        if result == VmResult::Ok {
+            // Scripts always return nil
+            self.stack.pop();
+        }
+        // Nothing caught in one script is rethrown by the next
+        self.thrown = None;
+        result
+    }
+
+    /// Compiles `source` without running it.
+    pub fn check(&mut self, source: &str) -> Result<(), CompilerError> {
+        let function = Function::new("Script", FunctionType::Script);
+        compile(source, function, &mut self.heap).map(|_| ())
+    }
+
+    /// Compiles `source` and lists the bytecode of the script and of every
+    /// function declared in it.
+    pub fn disassemble(&mut self, source: &str) -> Result<String, CompilerError> {
+        let function = Function::new("Script", FunctionType::Script);
+        let script = compile(source, function, &mut self.heap)?;
+        Ok(self.heap.get_function(&script).unwrap().disassemble(&self.heap))
+    }
+
+    /// Reports execution to `tracer` from now on.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Makes `args` available to scripts through the `argc` and `argv`
+    /// natives.
+    pub fn set_args(&mut self, args: &[String]) {
+        self.heap.set_args(args);
+    }
+
+    /// Calls the global function `name` with `args` and returns its result.
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        let callee = match self.get_global(name) {
+            Some(callee) => callee,
+            None => {
+                return Err(RuntimeError::new(&format!(
+                    "Undefined variable '{}'.",
+                    name
+                )))
+            }
+        };
+
+        self.stack.push(callee);
+        for arg in args {
+            self.stack.push(*arg);
+        }
+
+        match self.call_value(callee, args.len()) {
+            Ok(FunctionType::Function) => match self.run() {
+                VmResult::RuntimeError(error) => Err(error),
+                _ => Ok(self.stack.pop()),
+            },
+            // Natives and classes without an initializer finish immediately
+            Ok(_) => Ok(self.stack.pop()),
+            Err(error) => {
+                self.reset();
+                Err(error)
+            }
+        }
+    }
+
+    /// Makes a Rust function callable from Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: Arity, function: NativeFn) {
+        let native = NativeFunction::new(name, arity, function);
+        let native = self.allocate(Object::NativeFunction(native));
+        self.define_global(name, Value::Object(native));
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        let name = self.heap.intern(name);
+        self.globals.insert(name, value);
+    }
+
+    pub fn get_global(&mut self, name: &str) -> Option<Value> {
+        let name = self.heap.intern(name);
+        self.globals.get(&name).copied()
+    }
+
+    /// Allocates a Lox string. Like every other object it is only kept alive
+    /// while something in the VM refers to it.
+    pub fn new_string(&mut self, s: &str) -> Value {
+        Value::Object(self.heap.intern(s))
+    }
+
+    pub fn get_string(&self, value: &Value) -> Option<&str> {
+        match value {
+            Value::Object(id) => self.heap.get_string(id).map(String::as_str),
+            _ => None,
         }
     }
 
+    /// Formats a value the way `print` would.
+    pub fn format_value(&self, value: &Value) -> String {
+        self.heap.format_value(value)
+    }
+
+    fn allocate(&mut self, object: Object) -> ObjectId {
+        self.heap.add_value(object)
+    }
+
     fn frame_mut(&mut self) -> &mut CallFrame {
         self.frames.last_mut().unwrap()
     }
@@ -97,315 +277,235 @@ impl Vm {
         self.frames.last().unwrap()
     }
 
-    fn run(&mut self, strings: &mut HashSet<String>) -> VmResult {
-        let mut globals: HashMap<String, Value> = HashMap::new();
-        // Define native functions here
-        let clock = clock();
-        globals.insert(
-            "clock".to_string(),
-            Value::Object(Object::NativeFunction(clock)),
-        );
+    fn function(&self) -> &Function {
+        self.heap
+            .get_function(&self.frame().function)
+            .expect("Call frame refers to a function that does not exist")
+    }
 
-        let mut stack: Stack = Stack::new();
+    fn closure(&self) -> &Closure {
+        match self.heap.get(&self.frame().closure) {
+            Some(Object::Closure(closure)) => closure,
+            _ => panic!("Call frame refers to a closure that does not exist"),
+        }
+    }
+
+    fn read_constant(&self, index: usize) -> Value {
+        self.function().chunk.constants[index]
+    }
 
-        if cfg!(feature = "debug") {
-            self.print_iseq();
+    fn read_string(&self, index: usize) -> String {
+        match self.read_constant(index) {
+            Value::Object(id) => self.heap.get_string(&id).cloned().unwrap_or_default(),
+            _ => String::new(),
         }
+    }
 
+    /// Global names are interned strings, so the constant's id is the key.
+    fn read_name(&self, index: usize) -> ObjectId {
+        match self.read_constant(index) {
+            Value::Object(id) => id,
+            _ => panic!("Variable name constant is not a string"),
+        }
+    }
+
+    /// Runs until the frame on top when called returns, leaving its return
+    /// value on the stack.
+    fn run(&mut self) -> VmResult {
+        let base = self.frames.len();
+        let result = loop {
+            match self.dispatch(base) {
+                // Only errors a `try` will catch are turned into values
+                VmResult::RuntimeError(error) if self.has_handler(base) => {
+                    let value = self.error_value(&error);
+                    self.unwind(value, base);
+                    self.thrown = Some((value, error));
+                }
+                result => break result,
+            }
+        };
+
+        if result != VmResult::Ok {
+            self.reset();
+        }
+        result
+    }
+
+    /// Executes instructions until the frame `run` started with returns or
+    /// something goes wrong.
+    fn dispatch(&mut self, base: usize) -> VmResult {
         loop {
-            let mut step = 1;
+            // Every live object is reachable from a root between instructions
+            if self.should_collect() {
+                self.collect_garbage();
+            }
+
+            // Step past the instruction first so jumps and calls start
+            // from the next one
             let ip = self.frame().ip;
-            let op_code = &self.frame().code_at(ip).clone();
+            let (op_code, next) = match OpCode::decode(&self.function().chunk.code, ip) {
+                Some(decoded) => decoded,
+                None => panic!("Invalid instruction at offset {}", ip),
+            };
+            self.frame_mut().ip = next;
 
-            if cfg!(feature = "debug") {
-                stack.print_stack();
-                self.print_globals(&globals);
-                OpCode::disassemble_instruction(op_code, &self.frame().chunk(), ip);
+            if self.tracing(TraceKind::Exec) {
+                self.trace_instruction(&op_code, ip);
             }
 
             match op_code {
                 OpCode::Add => {
-                    let a = stack.peek(0).clone();
-                    let b = stack.peek(1).clone();
-                    match a {
-                        Value::Object(a) => match b {
-                            Value::Object(b) => {
-                                stack.pop();
-                                stack.pop();
-                                let mut new_string = b.to_string();
-                                new_string.push_str(&a.to_string());
-                                let new_object =
-                                    if let Some(existing_string) = strings.get(&new_string) {
-                                        existing_string.to_string()
-                                    } else {
-                                        new_string
-                                    };
-                                stack.push(Value::Object(Object::String(new_object)));
-                            }
-                            _ => {
-                                break VmResult::RuntimeError(String::from(
-                                    "Operand must be a number.",
-                                ))
-                            }
-                        },
-                        Value::HeapObject(a_key) => match b {
-                            Value::HeapObject(b_key) => {
-                                stack.pop();
-                                stack.pop();
-                                match self.heap.remove(a_key) {
-                                    Some(Object::String(a)) => {
-                                        match self.heap.get_mut(b_key) {
-                                            Some(Object::String(b)) => {
-                                                dbg!(&b);
-                                                dbg!(&a);
-                                                b.push_str(&a);
-                                            }
-                                            _ => {
-                                                break VmResult::RuntimeError(String::from(
-                                                    "Operand must be a number.",
-                                                ))
-                                            }
-                                        };
-                                    }
+                    let a = *self.stack.peek(0);
+                    let b = *self.stack.peek(1);
+                    match (a, b) {
+                        (Value::Object(a), Value::Object(b)) => {
+                            let concatenated =
+                                match (self.heap.get_string(&a), self.heap.get_string(&b)) {
+                                    (Some(a), Some(b)) => format!("{}{}", b, a),
                                     _ => {
-                                        break VmResult::RuntimeError(String::from(
-                                            "Operand must be a number.",
+                                        break self.runtime_error(String::from(
+                                            "Operands must be two numbers or two strings.",
                                         ))
                                     }
                                 };
-                                stack.push(Value::HeapObject(b_key));
-                            }
-                            _ => {
-                                break VmResult::RuntimeError(String::from(
-                                    "Operand must be a number.",
-                                ))
-                            }
-                        },
-                        _ => bin_op!(stack, +),
+                            self.stack.pop();
+                            self.stack.pop();
+                            let key = self.heap.intern_owned(concatenated);
+                            self.stack.push(Value::Object(key));
+                        }
+                        (Value::Object(_), _) | (_, Value::Object(_)) => {
+                            break self.runtime_error(String::from(
+                                "Operands must be two numbers or two strings.",
+                            ))
+                        }
+                        _ => bin_op!(self, +, "Operands must be two numbers or two strings."),
                     }
                 }
                 OpCode::Subtract => {
-                    bin_op!(stack, -);
+                    bin_op!(self, -);
                 }
                 OpCode::Multiply => {
-                    bin_op!(stack, *);
+                    bin_op!(self, *);
                 }
                 OpCode::Divide => {
-                    bin_op!(stack, /);
+                    bin_op!(self, /);
                 }
                 OpCode::Negate => {
-                    let value = stack.peek(0);
+                    let value = self.stack.peek(0);
                     if value.is_number() {
-                        let a = stack.pop();
+                        let a = self.stack.pop();
                         match -a {
-                            Ok(value) => stack.push(value),
+                            Ok(value) => self.stack.push(value),
                             Err(_) => {
-                                break VmResult::RuntimeError(String::from(
+                                break self.runtime_error(String::from(
                                     "This is unreachable code. How you got here no one knows.",
                                 ));
                             }
                         }
                     } else {
-                        break VmResult::RuntimeError(String::from("Operand must be a number."));
+                        break self.runtime_error(String::from("Operand must be a number."));
                     }
                 }
                 OpCode::Not => {
-                    let a = stack.pop();
-                    stack.push(Value::Bool(a.is_falsey()));
+                    let a = self.stack.pop();
+                    self.stack.push(Value::Bool(a.is_falsey()));
                 }
                 OpCode::Nil => {
-                    stack.push(Value::Nil);
+                    self.stack.push(Value::Nil);
                 }
                 OpCode::True => {
-                    stack.push(Value::Bool(true));
+                    self.stack.push(Value::Bool(true));
                 }
                 OpCode::False => {
-                    stack.push(Value::Bool(false));
+                    self.stack.push(Value::Bool(false));
                 }
                 OpCode::Equal => {
-                    let a = stack.pop();
-                    let b = stack.pop();
-                    stack.push(Value::Bool(a == b));
-                }
-                OpCode::Greater => {
-                    let a = stack.pop();
-                    let b = stack.pop();
-                    stack.push(Value::Bool(b > a));
+                    let a = self.stack.pop();
+                    let b = self.stack.pop();
+                    self.stack.push(Value::Bool(a == b));
                 }
-                OpCode::Less => {
-                    let a = stack.pop();
-                    let b = stack.pop();
-                    stack.push(Value::Bool(b < a));
-                }
-                OpCode::Constant(index) => {
-                    let constant = self.frame().get_constant(*index).unwrap();
-                    stack.push(constant.clone());
+                OpCode::Greater => match self.pop_numbers() {
+                    Some((a, b)) => self.stack.push(Value::Bool(a > b)),
+                    None => break self.runtime_error(String::from("Operands must be numbers.")),
+                },
+                OpCode::Less => match self.pop_numbers() {
+                    Some((a, b)) => self.stack.push(Value::Bool(a < b)),
+                    None => break self.runtime_error(String::from("Operands must be numbers.")),
+                },
+                OpCode::Constant(index) | OpCode::ConstantLong(index) => {
+                    self.stack.push(self.read_constant(index));
                 }
                 OpCode::Print => {
-                    let value = stack.pop();
-                    println!("{}", value);
+                    let value = self.stack.pop();
+                    println!("{}", self.heap.format_value(&value));
                 }
                 OpCode::Pop => {
-                    stack.pop();
-                }
-                OpCode::DefineGlobal(index) => {
-                    let constant = self.frame().get_constant(*index);
-                    if let Some(constant) = constant {
-                        match constant {
-                            Value::HeapObject(id) => {
-                                let value = stack.peek(0).clone();
-                                let s = match self.heap.get(*id) {
-                                    Some(Object::String(s)) => s,
-                                    _ => {
-                                        break VmResult::RuntimeError(String::from(
-                                            "Cannot resolve variable name.",
-                                        ))
-                                    }
-                                };
-                                globals.insert(s.clone(), value);
-                                stack.pop();
-                            }
-                            Value::Object(obj) => {
-                                let value = stack.peek(0).clone();
-                                let s = match obj {
-                                    Object::String(s) => s,
-                                    _ => {
-                                        break VmResult::RuntimeError(String::from(
-                                            "Cannot resolve variable name.",
-                                        ))
-                                    }
-                                };
-                                globals.insert(s.clone(), value);
-                                stack.pop();
-                            }
-                            _ => {
-                                break VmResult::RuntimeError(String::from(
-                                    "Cannot resolve variable name.",
-                                ))
-                            }
-                        }
-                    }
+                    self.stack.pop();
                 }
-                OpCode::GetGlobal(index) => {
-                    let constant = self.frame().get_constant(*index);
-                    if let Some(constant) = constant {
-                        match constant {
-                            Value::HeapObject(key) => {
-                                let s = match self.heap.get(*key) {
-                                    Some(Object::String(s)) => s,
-                                    _ => {
-                                        break VmResult::RuntimeError(String::from(
-                                            "Cannot resolve variable name.",
-                                        ))
-                                    }
-                                };
-                                let value = globals.get(s);
-                                match value {
-                                    Some(value) => stack.push(value.clone()),
-                                    _ => {
-                                        break VmResult::RuntimeError(
-                                            "Cannot resolve variable name.".to_string(),
-                                        )
-                                    }
-                                }
-                            }
-                            Value::Object(obj) => {
-                                let s = match obj {
-                                    Object::String(s) => s,
-                                    _ => {
-                                        break VmResult::RuntimeError(
-                                            "Cannot resolve variabl name.".to_string(),
-                                        )
-                                    }
-                                };
-                                let value = globals.get(s);
-                                match value {
-                                    Some(value) => stack.push(value.clone()),
-                                    _ => {
-                                        break VmResult::RuntimeError(
-                                            "Cannot resolve variable name.".to_string(),
-                                        )
-                                    }
-                                }
-                            }
-                            _ => {
-                                break VmResult::RuntimeError(
-                                    "Cannot resolve variable name.".to_string(),
-                                )
-                            }
+                OpCode::DefineGlobal(index) | OpCode::DefineGlobalLong(index) => {
+                    let name = self.read_name(index);
+                    self.globals.insert(name, self.stack.pop());
+                }
+                OpCode::GetGlobal(index) | OpCode::GetGlobalLong(index) => {
+                    let name = self.read_name(index);
+                    match self.globals.get(&name) {
+                        Some(value) => self.stack.push(*value),
+                        None => {
+                            break self.runtime_error(format!(
+                                "Undefined variable '{}'.",
+                                self.read_string(index)
+                            ))
                         }
                     }
                 }
-                OpCode::SetGlobal(index) => {
-                    let constant = self.frame().get_constant(*index);
-                    if let Some(constant) = constant {
-                        match constant {
-                            Value::Object(obj) => {
-                                let value = stack.peek(0);
-                                let s = match obj {
-                                    Object::String(s) => s,
-                                    _ => {
-                                        let error = format!("Undefined variable '{}'", value);
-                                        break VmResult::RuntimeError(error);
-                                    }
-                                };
-                                match globals.insert(s.clone(), value.clone()) {
-                                    Some(_) => (),
-                                    None => {
-                                        let error = format!("Undefined variable '{}'", s);
-                                        globals.remove(s);
-                                        break VmResult::RuntimeError(error);
-                                    }
-                                }
-                            }
-                            Value::HeapObject(key) => {
-                                let value = stack.peek(0).clone();
-                                let s = match self.heap.get(*key) {
-                                    Some(Object::String(s)) => s,
-                                    _ => {
-                                        let error = format!("Undefined variable '{}'", value);
-                                        break VmResult::RuntimeError(error);
-                                    }
-                                };
-                                match globals.insert(s.clone(), value) {
-                                    Some(_) => (),
-                                    None => {
-                                        let error = format!("Undefined variable '{}'", s);
-                                        globals.remove(s);
-                                        break VmResult::RuntimeError(error);
-                                    }
-                                }
-                            }
-                            _ => {
-                                break VmResult::RuntimeError(
-                                    "Cannot resolve variable name.".to_string(),
-                                )
-                            }
+                OpCode::SetGlobal(index) | OpCode::SetGlobalLong(index) => {
+                    let name = self.read_name(index);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = *self.stack.peek(0),
+                        None => {
+                            break self.runtime_error(format!(
+                                "Undefined variable '{}'.",
+                                self.read_string(index)
+                            ))
                         }
                     }
                 }
-                OpCode::SetLocal(index) => {
+                OpCode::SetLocal(index) | OpCode::SetLocalLong(index) => {
                     let slots = self.frame().slots;
-                    let value = stack.peek(0);
-                    stack[slots + *index] = value.clone();
+                    self.stack[slots + index] = *self.stack.peek(0);
                 }
-                OpCode::GetLocal(index) => {
+                OpCode::GetLocal(index) | OpCode::GetLocalLong(index) => {
                     let slots = self.frame().slots;
-                    let value = stack[slots + *index].clone();
-                    stack.push(value);
+                    self.stack.push(self.stack[slots + index]);
                 }
-                OpCode::SetUpvalue(index) => {
-                    let slots = self.frame().slots;
-                    let value = stack.peek(0);
-                    dbg!(slots + *index);
-                    stack[slots + *index] = value.clone();
+                OpCode::SetUpvalue(index) | OpCode::SetUpvalueLong(index) => {
+                    let key = self.closure().upvalues[index];
+                    let value = *self.stack.peek(0);
+                    if let Some(Object::Upvalue(upvalue)) = self.heap.get_mut(&key) {
+                        if upvalue.is_open() {
+                            self.stack[upvalue.location] = value;
+                        } else {
+                            upvalue.close(value);
+                        }
+                    }
                 }
-                OpCode::GetUpvalue(index) => {
-                    let value = self.frame().closure.upvalues.get(*index).unwrap();
-                    // stack.push(*value.location);
+                OpCode::GetUpvalue(index) | OpCode::GetUpvalueLong(index) => {
+                    let key = self.closure().upvalues[index];
+                    let value = match self.heap.get(&key) {
+                        Some(Object::Upvalue(upvalue)) => match upvalue.closed {
+                            Some(value) => value,
+                            None => self.stack[upvalue.location],
+                        },
+                        _ => panic!("Closure referenced an upvalue that does not exist"),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.top() - 1);
+                    self.stack.pop();
                 }
                 OpCode::JumpIfFalse(offset) => {
-                    let value = stack.peek(0);
+                    let value = self.stack.peek(0);
                     if value.is_falsey() {
                         self.frame_mut().ip += offset;
                     }
@@ -414,240 +514,706 @@ impl Vm {
                     self.frame_mut().ip += offset;
                 }
                 OpCode::Loop(offset) => {
-                    self.frame_mut().ip -= offset + 1;
-                }
-                OpCode::Call(arg_count) => {
-                    let value = stack.peek(*arg_count).clone();
-                    let (result, function_type) = self.call_value(&mut stack, value, *arg_count);
-                    if !result {
-                        return VmResult::RuntimeError(
-                            "An error occurred calling a function.".to_string(),
-                        );
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Call(arg_count) | OpCode::CallLong(arg_count) => {
+                    let value = *self.stack.peek(arg_count);
+                    match self.call_value(value, arg_count) {
+                        Ok(FunctionType::Function) => continue,
+                        Ok(_) => (),
+                        Err(error) => break self.runtime_error(error.message),
+                    }
+                }
+                OpCode::Closure(index) | OpCode::ClosureLong(index) => {
+                    let function = match self.read_constant(index) {
+                        Value::Object(function) => function,
+                        _ => panic!("Received a value that was not a function!"),
+                    };
+                    let mut closure = Closure::new(function);
+                    self.capture_upvalues(&mut closure, ip);
+                    let key = self.allocate(Object::Closure(closure));
+                    self.stack.push(Value::Object(key))
+                }
+                OpCode::Class(index) | OpCode::ClassLong(index) => {
+                    let name = self.read_string(index);
+                    let key = self.allocate(Object::Class(Class::new(&name)));
+                    self.stack.push(Value::Object(key));
+                }
+                OpCode::GetProperty(index) | OpCode::GetPropertyLong(index) => {
+                    let name = self.read_string(index);
+                    let instance_key = match self.stack.peek(0) {
+                        Value::Object(key) => *key,
+                        _ => {
+                            break self.runtime_error(String::from(
+                                "Only instances have properties.",
+                            ))
+                        }
+                    };
+                    let (field, class_key) = match self.heap.get(&instance_key) {
+                        Some(Object::Instance(instance)) => {
+                            (instance.fields.get(&name).copied(), instance.class)
+                        }
+                        _ => {
+                            break self.runtime_error(String::from(
+                                "Only instances have properties.",
+                            ))
+                        }
+                    };
+
+                    if let Some(value) = field {
+                        self.stack.pop();
+                        self.stack.push(value);
+                    } else if let Some(bound) = self.bind_method(class_key, instance_key, &name) {
+                        self.stack.pop();
+                        self.stack.push(bound);
                     } else {
-                        if function_type == FunctionType::Function {
-                            continue;
+                        break self.runtime_error(format!("Undefined property '{}'.", name));
+                    }
+                }
+                OpCode::SetProperty(index) | OpCode::SetPropertyLong(index) => {
+                    let name = self.read_string(index);
+                    let value = self.stack.pop();
+                    let instance = match self.stack.peek(0) {
+                        Value::Object(key) => self.heap.get_mut(key),
+                        _ => None,
+                    };
+                    match instance {
+                        Some(Object::Instance(instance)) => {
+                            instance.fields.insert(name, value);
+                        }
+                        _ => {
+                            break self.runtime_error(String::from(
+                                "Only instances have fields.",
+                            ))
                         }
                     }
+                    self.stack.pop();
+                    self.stack.push(value);
                 }
-                OpCode::Closure(index) => {
-                    let constant = self.frame().get_constant(*index).unwrap();
-                    match constant {
-                        Value::Object(obj) => {
-                            match obj {
-                                Object::Closure(closure) => {
-                                    let mut new_closure = closure.clone();
-                                    for _ in 0..(new_closure.upvalue_count) {
-                                        let variable = self.frame().code_at(ip + step);
-                                        match variable {
-                                            OpCode::LocalValue(index) => {
-                                                let upvalue = self
-                                                    .capture_upvalue(&stack, self.frame().slots + index);
-                                                new_closure.upvalues.push(upvalue);
-                                                step += 1;
-                                            }
-                                            OpCode::Upvalue(index) => {
-                                                new_closure.upvalues.push(
-                                                    self.frame()
-                                                        .closure
-                                                        .upvalues
-                                                        .get(*index)
-                                                        .unwrap()
-                                                        .clone(),
-                                                );
-                                                step += 1;
-                                            }
-                                            _ => {
-                                                panic!("Tried to resolve an upvalue but received an unexpected instruction")
-                                            }
-                                        }
-                                    }
-                                    stack.push(Value::Object(Object::Closure(new_closure)))
-                                },
-                                _ => {
-                                    panic!("Received a value that was not a function!")
-                                }
-                            }
+                OpCode::Method(index) | OpCode::MethodLong(index) => {
+                    let name = self.read_string(index);
+                    let method = match self.stack.pop() {
+                        Value::Object(key) => key,
+                        _ => panic!("Received a method that was not a closure!"),
+                    };
+                    if let Value::Object(key) = self.stack.peek(0) {
+                        if let Some(Object::Class(class)) = self.heap.get_mut(key) {
+                            class.methods.insert(name, method);
                         }
-                        Value::HeapObject(key) => {
-                            let closure = match self.heap.get(*key) {
-                                Some(Object::Closure(closure)) => closure,
-                                _ => panic!("Attempted to execute closure but none found"),
-                            };
-                            let mut new_closure = closure.clone();
-                            for _ in 0..(new_closure.upvalue_count) {
-                                let variable = self.frame().code_at(ip + step);
-                                match variable {
-                                    OpCode::LocalValue(index) => {
-                                        let upvalue = self
-                                            .capture_upvalue(&stack, self.frame().slots + index);
-                                        new_closure.upvalues.push(upvalue);
-                                        step += 1;
-                                    }
-                                    OpCode::Upvalue(index) => {
-                                        new_closure.upvalues.push(
-                                            self.frame()
-                                                .closure
-                                                .upvalues
-                                                .get(*index)
-                                                .unwrap()
-                                                .clone(),
-                                        );
-                                        step += 1;
-                                    }
-                                    _ => {
-                                        panic!("Tried to resolve an upvalue but received an unexpected instruction")
-                                    }
-                                }
-                            }
-                            let key = self.heap.insert(Object::Closure(new_closure));
-                            stack.push(Value::HeapObject(key))
+                    }
+                }
+                OpCode::Inherit => {
+                    // The superclass stays on the stack as the hidden `super` local
+                    let superclass = match *self.stack.peek(1) {
+                        Value::Object(key)
+                            if matches!(self.heap.get(&key), Some(Object::Class(_))) =>
+                        {
+                            key
+                        }
+                        _ => {
+                            break self.runtime_error(String::from(
+                                "Superclass must be a class.",
+                            ))
+                        }
+                    };
+                    if let Value::Object(key) = self.stack.pop() {
+                        if let Some(Object::Class(class)) = self.heap.get_mut(&key) {
+                            class.superclass = Some(superclass);
                         }
-                        _ => panic!("Received a value that was not a function!"),
                     }
                 }
-                OpCode::LocalValue(_) => {
-                    // panic!("Local value opcode was attempted to be executed")
+                OpCode::GetSuper(index) | OpCode::GetSuperLong(index) => {
+                    let name = self.read_string(index);
+                    let superclass = match self.stack.pop() {
+                        Value::Object(key) => Some(key),
+                        _ => None,
+                    };
+                    let receiver = match self.stack.peek(0) {
+                        Value::Object(key) => *key,
+                        _ => {
+                            break self.runtime_error(String::from(
+                                "Only instances have superclasses.",
+                            ))
+                        }
+                    };
+                    let bound = superclass.and_then(|class| self.bind_method(class, receiver, &name));
+                    match bound {
+                        Some(bound) => {
+                            self.stack.pop();
+                            self.stack.push(bound);
+                        }
+                        None => {
+                            break self.runtime_error(format!(
+                                "Undefined property '{}'.",
+                                name
+                            ))
+                        }
+                    }
+                }
+                OpCode::BuildList(count) => {
+                    let items = self.stack.peek_many(count).to_vec();
+                    let top = self.stack.top();
+                    self.stack.drain((top - count)..top);
+                    self.stack.set_top_by_offset(count);
+                    let key = self.allocate(Object::List(items));
+                    self.stack.push(Value::Object(key));
                 }
-                OpCode::Upvalue(_) => {
-                    // panic!("Upvalue opcode was attempted to be executed")
+                OpCode::BuildMap(count) => {
+                    let map = match self.build_map(count) {
+                        Ok(map) => map,
+                        Err(error) => break self.runtime_error(error.message),
+                    };
+                    let top = self.stack.top();
+                    self.stack.drain((top - 2 * count)..top);
+                    self.stack.set_top_by_offset(2 * count);
+                    let key = self.allocate(Object::Map(map));
+                    self.stack.push(Value::Object(key));
+                }
+                OpCode::GetIndex => {
+                    let index = self.stack.pop();
+                    let target = self.stack.pop();
+                    match self.get_index(target, index) {
+                        Ok(value) => self.stack.push(value),
+                        Err(error) => break self.runtime_error(error.message),
+                    }
+                }
+                OpCode::SetIndex => {
+                    let value = self.stack.pop();
+                    let index = self.stack.pop();
+                    let target = self.stack.pop();
+                    match self.set_index(target, index, value) {
+                        // Assignment is an expression, leave the value behind
+                        Ok(()) => self.stack.push(value),
+                        Err(error) => break self.runtime_error(error.message),
+                    }
                 }
                 OpCode::Return => {
                     // Get the return value and store temporarily
-                    let value = stack.pop();
-                    // if we only have one frame, it's the top level script
-                    if self.frames.len() == 1 {
-                        break VmResult::Ok;
+                    let value = self.stack.pop();
+                    if self.tracing(TraceKind::Calls) {
+                        let value = self.heap.format_value(&value);
+                        let name = self.frame_name().to_string();
+                        let depth = self.frames.len();
+                        self.tracer.as_mut().unwrap().ret(&name, depth, &value);
                     }
 
-                    // Need to reset the stack
-                    let top = stack.top();
-                    // Count number of slots and function
-                    let offset = (top - self.frame().slots) + 1;
-                    // Drop the references to the slots and function call
-                    stack.drain((self.frame().slots - 1)..top);
-                    // Set the stack top
-                    stack.set_top_by_offset(offset);
+                    // Drop the callee, its arguments and its locals
+                    let top = self.stack.top();
+                    let slots = self.frame().slots;
+                    self.close_upvalues(slots);
+                    self.stack.drain(slots..top);
+                    self.stack.set_top_by_offset(top - slots);
 
                     // Push return of function back onto the stack
-                    stack.push(value);
+                    self.stack.push(value);
 
                     // Remove the call frame
                     self.frames.pop();
+
+                    // The frame `run` started with hands its value to the caller
+                    if self.frames.len() < base {
+                        break VmResult::Ok;
+                    }
+                }
+                OpCode::Try(offset) => {
+                    let catch = self.frame().ip + offset;
+                    let stack_top = self.stack.top();
+                    self.frame_mut().handlers.push(Handler { catch, stack_top });
+                }
+                OpCode::PopHandler => {
+                    self.frame_mut().handlers.pop();
                 }
+                OpCode::Throw | OpCode::Rethrow => {
+                    let value = self.stack.pop();
+                    let error = match self.thrown.take() {
+                        Some((thrown, error)) if op_code == OpCode::Rethrow && thrown == value => error,
+                        _ => self.thrown_error(value),
+                    };
+                    if !self.unwind(value, base) {
+                        break VmResult::RuntimeError(error);
+                    }
+                    self.thrown = Some((value, error));
+                }
+            }
+        }
+    }
+
+    /// Pops the two operands of a comparison, left first, if both are
+    /// numbers. Otherwise leaves them for the error to report.
+    fn pop_numbers(&mut self) -> Option<(f64, f64)> {
+        match (*self.stack.peek(1), *self.stack.peek(0)) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.stack.pop();
+                self.stack.pop();
+                Some((a, b))
+            }
+            _ => None,
+        }
+    }
+
+    fn has_handler(&self, base: usize) -> bool {
+        self.frames[base - 1..].iter().any(|frame| !frame.handlers.is_empty())
+    }
+
+    /// Jumps to the innermost handler with `value` on the stack, dropping
+    /// the frames and values in between and closing their upvalues. Frames
+    /// below the one `run` started with are left alone, so returns `false`
+    /// without doing anything when no handler is found above it.
+    fn unwind(&mut self, value: Value, base: usize) -> bool {
+        if !self.has_handler(base) {
+            return false;
+        }
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            if let Some(handler) = frame.handlers.pop() {
+                frame.ip = handler.catch;
+                self.close_upvalues(handler.stack_top);
+                self.stack.truncate(handler.stack_top);
+                self.stack.push(value);
+                return true;
             }
+            let slots = frame.slots;
+            self.close_upvalues(slots);
+            self.stack.truncate(slots);
+            self.frames.pop();
+        }
+    }
+
+    /// An `Error` instance holding the message and trace of `error`.
+    fn error_value(&mut self, error: &RuntimeError) -> Value {
+        let message = self.heap.intern(&error.message);
+        let trace = error
+            .trace
+            .iter()
+            .map(|frame| Value::Object(self.heap.intern_owned(frame.to_string())))
+            .collect();
+        let trace = self.allocate(Object::List(trace));
 
-            self.frame_mut().ip += step;
+        let mut instance = Instance::new(self.error_class);
+        instance.fields.insert(String::from("message"), Value::Object(message));
+        instance.fields.insert(String::from("trace"), Value::Object(trace));
+        Value::Object(self.allocate(Object::Instance(instance)))
+    }
+
+    /// The error reported for `value` if nothing catches it. Instances with
+    /// a `message` report that, anything else is printed as is.
+    fn thrown_error(&self, value: Value) -> RuntimeError {
+        let message = match value {
+            Value::Object(key) => match self.heap.get(&key) {
+                Some(Object::Instance(instance)) => instance.fields.get("message").and_then(|m| self.get_string(m)),
+                _ => None,
+            },
+            _ => None,
+        };
+        match message {
+            Some(message) => self.error_with_trace(message.to_string()),
+            None => self.error_with_trace(self.heap.format_value(&value)),
         }
     }
 
+    fn runtime_error(&self, message: String) -> VmResult {
+        VmResult::RuntimeError(self.error_with_trace(message))
+    }
+
+    /// Builds an error carrying one trace entry per active call, innermost
+    /// first, each with the line of the instruction that frame was running.
+    fn error_with_trace(&self, message: String) -> RuntimeError {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.get_function(&frame.function).unwrap();
+                // Every frame has already stepped past its current instruction
+                let offset = frame.ip.saturating_sub(1);
+                let line = function.chunk.line(offset);
+                let span = function.chunk.span(offset);
+                let name = match function.function_type() {
+                    FunctionType::Script => None,
+                    _ => Some(function.name().clone()),
+                };
+                TraceFrame::new(name, line, span)
+            })
+            .collect();
+        RuntimeError::with_trace(&message, trace)
+    }
+
+    /// Unwinds everything after a runtime error. Globals survive so the next
+    /// `interpret` can carry on.
+    fn reset(&mut self) {
+        self.thrown = None;
+        self.frames.clear();
+        self.stack.reset();
+        self.open_upvalues.clear();
+    }
+
     fn call_value(
         &mut self,
-        stack: &mut Stack,
         callee: Value,
         arg_count: usize,
-    ) -> (bool, FunctionType) {
-        match callee {
-            Value::Object(obj) => {
-                match obj {
-                    Object::Closure(closure) => {
-                        (
-                            self.call(stack.top(), closure.clone(), arg_count),
-                            FunctionType::Function,
-                        )
-                    }
-                    Object::NativeFunction(function) => {
-                        let result = (function.function)();
-                        stack.pop();
-                        stack.push(result);
-                        (true, FunctionType::Native)
-                    }
-                    _ => panic!("Function not found")
+    ) -> Result<FunctionType, RuntimeError> {
+        let key = match callee {
+            Value::Object(key) => key,
+            _ => return Err(RuntimeError::new("Can only call functions and classes.")),
+        };
+        match self.heap.get(&key) {
+            Some(Object::Class(_)) => {
+                let initializer = self.find_method(key, "init");
+                let instance = self.allocate(Object::Instance(Instance::new(key)));
+                let callee = self.stack.top() - arg_count - 1;
+                self.stack[callee] = Value::Object(instance);
+
+                match initializer {
+                    Some(initializer) => self.call(self.stack.top(), initializer, arg_count),
+                    None if arg_count == 0 => Ok(FunctionType::Native),
+                    None => Err(RuntimeError::new(&format!(
+                        "Expected 0 arguments but got {}.",
+                        arg_count
+                    ))),
                 }
             }
-            Value::HeapObject(key) => {
-                let obj = self.heap.get(key).unwrap();
-                match obj {
-                    Object::Closure(closure) => {
-                        (
-                            self.call(stack.top(), closure.clone(), arg_count),
-                            FunctionType::Function,
-                        )
-                    }
-                    Object::NativeFunction(function) => {
-                        let result = (function.function)();
-                        stack.pop();
-                        stack.push(result);
-                        (true, FunctionType::Native)
-                    }
-                    _ => panic!("Function not found")
+            Some(Object::BoundMethod(bound)) => {
+                let method = bound.method;
+                let callee = self.stack.top() - arg_count - 1;
+                self.stack[callee] = Value::Object(bound.receiver);
+                self.call(self.stack.top(), method, arg_count)
+            }
+            Some(Object::Closure(_)) => self.call(self.stack.top(), key, arg_count),
+            Some(Object::NativeFunction(native)) => {
+                let native = native.clone();
+                let depth = self.frames.len() + 1;
+                let traced = self.tracer.as_ref().is_some_and(|t| t.wants(TraceKind::Calls, &native.name));
+                if traced {
+                    self.tracer.as_mut().unwrap().call(&native.name, depth);
+                }
+                let result = native.call(&mut self.heap, self.stack.peek_many(arg_count))?;
+                if traced {
+                    let value = self.heap.format_value(&result);
+                    self.tracer.as_mut().unwrap().ret(&native.name, depth, &value);
                 }
+
+                // Drop the native and its arguments
+                let top = self.stack.top();
+                self.stack.drain((top - arg_count - 1)..top);
+                self.stack.set_top_by_offset(arg_count + 1);
+                self.stack.push(result);
+                Ok(FunctionType::Native)
             }
-            _ => (false, FunctionType::Script),
+            _ => Err(RuntimeError::new("Can only call functions and classes.")),
         }
     }
 
-    fn call(&mut self, stack_top: usize, closure: Closure, arg_count: usize) -> bool {
-        let arity = closure.function.arity;
+    fn call(
+        &mut self,
+        stack_top: usize,
+        closure: ObjectId,
+        arg_count: usize,
+    ) -> Result<FunctionType, RuntimeError> {
+        let function = match self.heap.get(&closure) {
+            Some(Object::Closure(closure)) => closure.function,
+            _ => return Err(RuntimeError::new("Can only call functions and classes.")),
+        };
+        let arity = self.heap.get_function(&function).map_or(0, |f| f.arity);
         if arg_count != arity {
-            VmResult::RuntimeError(format!(
-                "Expected {} arguments but received {}",
+            return Err(RuntimeError::new(&format!(
+                "Expected {} arguments but got {}.",
                 arity, arg_count
-            ));
+            )));
         }
 
         if self.frames.len() > FRAMES_MAX {
-            return false;
+            return Err(RuntimeError::new("An error occurred calling a function."));
         }
 
         let frame = CallFrame {
             closure,
+            function,
             ip: 0,
-            slots: stack_top - arg_count,
+            slots: stack_top - arg_count - 1,
+            handlers: Vec::new(),
         };
 
         self.frames.push(frame);
 
-        if cfg!(feature = "debug") {
-            self.print_call_frame();
-            self.print_iseq();
+        if self.tracing(TraceKind::Calls) {
+            let name = self.frame_name().to_string();
+            let depth = self.frames.len();
+            self.tracer.as_mut().unwrap().call(&name, depth);
+        }
+
+        Ok(FunctionType::Function)
+    }
+
+    /// Reads the upvalue descriptors trailing the `Closure` instruction at
+    /// `ip` and fills in the new closure's upvalues.
+    fn capture_upvalues(&mut self, closure: &mut Closure, ip: usize) {
+        let descriptors: Vec<(bool, usize)> = self.function().chunk.upvalues(ip).collect();
+        for (local, index) in descriptors {
+            let upvalue = if local {
+                self.capture_upvalue(self.frame().slots + index)
+            } else {
+                self.closure().upvalues[index]
+            };
+            closure.upvalues.push(upvalue);
         }
+    }
+
+    /// Returns the open upvalue for a stack slot, creating one if no other
+    /// closure has captured that slot yet.
+    fn capture_upvalue(&mut self, location: usize) -> ObjectId {
+        let existing = self.open_upvalues.iter().find(|key| {
+            matches!(self.heap.get(key), Some(Object::Upvalue(upvalue)) if upvalue.location == location)
+        });
+        if let Some(key) = existing {
+            return *key;
+        }
+
+        let key = self.allocate(Object::Upvalue(UpvalueRef::new(location)));
+        self.open_upvalues.push(key);
+        key
+    }
 
-        true
+    /// Closes every open upvalue pointing at or above `last`, copying the
+    /// stack value into the upvalue itself.
+    fn close_upvalues(&mut self, last: usize) {
+        let heap = &mut self.heap;
+        let stack = &self.stack;
+        self.open_upvalues.retain(|key| match heap.get_mut(key) {
+            Some(Object::Upvalue(upvalue)) if upvalue.location >= last => {
+                upvalue.close(stack[upvalue.location]);
+                false
+            }
+            _ => true,
+        });
     }
 
-    fn capture_upvalue(&self, stack: &Stack, index: usize) -> UpvalueRef {
-        let slots = self.frame().slots - 1;
-        dbg!(&stack);
-        dbg!(slots);
-        dbg!(index);
-        let value = &stack[slots + index];
-        UpvalueRef::new(false, index)
+    /// Looks a method up on the class, walking the superclass chain until a
+    /// definition is found.
+    fn find_method(&self, class: ObjectId, name: &str) -> Option<ObjectId> {
+        let mut current = Some(class);
+        while let Some(key) = current {
+            match self.heap.get(&key) {
+                Some(Object::Class(class)) => {
+                    if let Some(method) = class.methods.get(name) {
+                        return Some(*method);
+                    }
+                    current = class.superclass;
+                }
+                _ => return None,
+            }
+        }
+        None
     }
 
-    fn print_call_frame(&mut self) {
-        println!("======== FRAME =======");
-        println!("{:?}", self.frame());
+    /// A map of the `count` key value pairs on top of the stack. Later keys
+    /// win, as if they had been assigned one at a time.
+    fn build_map(&self, count: usize) -> Result<Map, RuntimeError> {
+        let mut map = Map::new();
+        for pair in self.stack.peek_many(2 * count).chunks(2) {
+            let key = MapKey::new(&pair[0], &self.heap)?;
+            map.insert(key, pair[0], pair[1]);
+        }
+        Ok(map)
     }
 
-    fn print_globals(&self, globals: &HashMap<String, Value>) {
-        println!("======= GLOBALS =======");
-        for (name, value) in globals {
-            println!("[{} = {}]", name, value);
+    fn get_index(&self, target: Value, index: Value) -> Result<Value, RuntimeError> {
+        let target = match target {
+            Value::Object(key) => self.heap.get(&key),
+            _ => None,
+        };
+        match target {
+            Some(Object::List(items)) => Ok(items[natives::list_index(&index, items.len())?]),
+            Some(Object::Map(map)) => match map.get(&MapKey::new(&index, &self.heap)?) {
+                Some(value) => Ok(value),
+                None => Err(RuntimeError::new(&format!(
+                    "Undefined key '{}'.",
+                    self.heap.format_value(&index)
+                ))),
+            },
+            _ => Err(RuntimeError::new("Only lists and maps can be indexed.")),
         }
     }
 
-    fn print_iseq(&mut self) {
-        self.frame_mut().closure.function.disassemble();
+    fn set_index(&mut self, target: Value, index: Value, value: Value) -> Result<(), RuntimeError> {
+        let target = match target {
+            Value::Object(key) => key,
+            _ => return Err(RuntimeError::new("Only lists and maps can be indexed.")),
+        };
+        // Hash before borrowing the map, a key has to be looked up on the heap
+        let map_key = match self.heap.get(&target) {
+            Some(Object::Map(_)) => Some(MapKey::new(&index, &self.heap)?),
+            _ => None,
+        };
+        match self.heap.get_mut(&target) {
+            Some(Object::List(items)) => {
+                let position = natives::list_index(&index, items.len())?;
+                items[position] = value;
+                Ok(())
+            }
+            Some(Object::Map(map)) => {
+                map.insert(map_key.unwrap(), index, value);
+                Ok(())
+            }
+            _ => Err(RuntimeError::new("Only lists and maps can be indexed.")),
+        }
+    }
+
+    fn bind_method(&mut self, class: ObjectId, receiver: ObjectId, name: &str) -> Option<Value> {
+        let method = self.find_method(class, name)?;
+        let bound = BoundMethod::new(receiver, method);
+        Some(Value::Object(self.allocate(Object::BoundMethod(bound))))
+    }
+
+    /// The script and every function nested in it, outermost first.
+    fn compiled_functions(&self, script: ObjectId) -> Vec<ObjectId> {
+        let mut functions = Vec::new();
+        let mut pending = vec![script];
+        while let Some(id) = pending.pop() {
+            functions.push(id);
+            let function = self.heap.get_function(&id).unwrap();
+            for constant in function.chunk.constants.iter().rev() {
+                if let Value::Object(constant) = constant {
+                    if self.heap.get_function(constant).is_some() {
+                        pending.push(*constant);
+                    }
+                }
+            }
+        }
+        functions
+    }
+
+    /// The name tracers know the running function by.
+    fn frame_name(&self) -> &str {
+        trace_name(self.function())
+    }
+
+    fn tracing(&self, kind: TraceKind) -> bool {
+        match &self.tracer {
+            Some(tracer) => !self.frames.is_empty() && tracer.wants(kind, self.frame_name()),
+            None => false,
+        }
+    }
+
+    fn trace_code(&mut self, script: ObjectId) {
+        if self.tracer.is_none() {
+            return;
+        }
+        for id in self.compiled_functions(script) {
+            let function = self.heap.get_function(&id).unwrap();
+            let name = trace_name(function);
+            if self.tracer.as_ref().unwrap().wants(TraceKind::Code, name) {
+                let listing = function.chunk.disassemble(function.name(), &self.heap);
+                self.tracer.as_mut().unwrap().code(name, &listing);
+            }
+        }
+    }
+
+    fn trace_instruction(&mut self, op_code: &OpCode, ip: usize) {
+        let name = self.frame_name().to_string();
+        let instruction = OpCode::disassemble_instruction(op_code, &self.function().chunk, ip, &self.heap);
+        let stack: Vec<String> = self
+            .stack
+            .as_slice()
+            .iter()
+            .map(|value| self.heap.format_value(value))
+            .collect();
+        self.tracer.as_mut().unwrap().instruction(&name, &instruction, &stack);
     }
 }
 
-fn clock() -> NativeFunction {
-    let closure = || match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => Value::Number(n.as_secs() as f32),
-        _ => Value::Number(0f32),
-    };
-    NativeFunction {
-        name: "clock".to_string(),
-        function: closure,
+fn trace_name(function: &Function) -> &str {
+    match function.function_type() {
+        FunctionType::Script => "script",
+        _ => function.name(),
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn collects_unreachable_objects() {
+        let mut vm = Vm::new();
+        let source = "class Node {} for (var i = 0; i < 5000; i = i + 1) { Node(); }";
+        assert_eq!(vm.interpret(source), VmResult::Ok);
+        assert!(vm.heap.len() < 5000);
+    }
+
+    #[test]
+    fn leaves_stack_empty_between_calls() {
+        let mut vm = Vm::new();
+        assert_eq!(vm.interpret("fun id(value) { var copy = value; return copy; } id(1);"), VmResult::Ok);
+        assert_eq!(vm.stack.top(), 0);
+        assert!(vm.frames.is_empty());
+        assert!(vm.call_function("id", &[Value::Nil]).is_ok());
+        assert_eq!(vm.stack.top(), 0);
+    }
+
+    /// Records events as strings so tests can see what was traced.
+    struct Recorder {
+        events: Rc<RefCell<Vec<String>>>,
+        function: &'static str,
+    }
+
+    impl Tracer for Recorder {
+        fn wants(&self, _kind: TraceKind, function: &str) -> bool {
+            function == self.function
+        }
+
+        fn code(&mut self, function: &str, _listing: &str) {
+            self.events.borrow_mut().push(format!("code {}", function));
+        }
+
+        fn instruction(&mut self, function: &str, _instruction: &str, stack: &[String]) {
+            self.events.borrow_mut().push(format!("exec {} {}", function, stack.len()));
+        }
+
+        fn call(&mut self, function: &str, depth: usize) {
+            self.events.borrow_mut().push(format!("call {} {}", function, depth));
+        }
+
+        fn ret(&mut self, function: &str, depth: usize, value: &str) {
+            self.events.borrow_mut().push(format!("return {} {} {}", function, depth, value));
+        }
+    }
+
+    #[test]
+    fn writes_disassembly_to_any_writer() {
+        let mut vm = Vm::new();
+        let function = Function::new("Script", FunctionType::Script);
+        let script = compile("fun f() { return 1; }", function, &mut vm.heap).unwrap();
+        let mut out = Vec::new();
+        let function = vm.heap.get_function(&script).unwrap();
+        function.write_disassembly(&vm.heap, &mut out).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        assert_eq!(listing, vm.disassemble("fun f() { return 1; }").unwrap());
+        assert!(listing.contains("==== f ===="));
+    }
+
+    #[test]
+    fn traces_only_the_filtered_function() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::new();
+        vm.set_tracer(Box::new(Recorder {
+            events: Rc::clone(&events),
+            function: "twice",
+        }));
+        let source = "fun twice(n) { return n * 2; } twice(1); twice(2);";
+        assert_eq!(vm.interpret(source), VmResult::Ok);
+        let events = events.borrow();
+        assert_eq!(events[0], "code twice");
+        assert_eq!(events[1], "call twice 2");
+        assert_eq!(events.iter().filter(|e| e.starts_with("exec twice")).count(), 8);
+        assert!(events.contains(&"return twice 2 4".to_string()));
+        assert!(events.iter().all(|e| !e.contains("script")));
     }
 }
@@ -0,0 +1,214 @@
+use std::time::SystemTime;
+
+use crate::core::{Arity, MapKey, NativeFunction, Object, RuntimeError, Value};
+use crate::vm::{Heap, ObjectId};
+
+/// The natives every script starts with.
+pub fn standard_library() -> Vec<NativeFunction> {
+    vec![
+        NativeFunction::new("clock", Arity::Fixed(0), clock),
+        NativeFunction::new("sqrt", Arity::Fixed(1), sqrt),
+        NativeFunction::new("max", Arity::Variadic(1), max),
+        NativeFunction::new("argc", Arity::Fixed(0), argc),
+        NativeFunction::new("argv", Arity::Fixed(1), argv),
+        NativeFunction::new("len", Arity::Fixed(1), len),
+        NativeFunction::new("push", Arity::Fixed(2), push),
+        NativeFunction::new("pop", Arity::Fixed(1), pop),
+        NativeFunction::new("insert", Arity::Fixed(3), insert),
+        NativeFunction::new("remove", Arity::Fixed(2), remove),
+        NativeFunction::new("slice", Arity::Variadic(2), slice),
+        NativeFunction::new("has", Arity::Fixed(2), has),
+        NativeFunction::new("delete", Arity::Fixed(2), delete),
+        NativeFunction::new("keys", Arity::Fixed(1), keys),
+        NativeFunction::new("values", Arity::Fixed(1), values),
+    ]
+}
+
+fn clock(_heap: &mut Heap, _args: &[Value]) -> Result<Value, RuntimeError> {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => Ok(Value::Number(n.as_secs_f64())),
+        _ => Ok(Value::Number(0f64)),
+    }
+}
+
+fn sqrt(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::Number(n) => Ok(Value::Number(n.sqrt())),
+        _ => Err(RuntimeError::new("Argument must be a number.")),
+    }
+}
+
+fn max(_heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut max = f64::NEG_INFINITY;
+    for arg in args {
+        match arg {
+            Value::Number(n) => max = max.max(*n),
+            _ => return Err(RuntimeError::new("Arguments must be numbers.")),
+        }
+    }
+    Ok(Value::Number(max))
+}
+
+/// The number of command line arguments passed to the script.
+fn argc(heap: &mut Heap, _args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::from(heap.args().len() as f64))
+}
+
+/// The script argument at an index, or nil past the end.
+fn argv(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => {
+            Ok(heap.args().get(n as usize).map_or(Value::Nil, |id| Value::Object(*id)))
+        }
+        _ => Err(RuntimeError::new("Argument must be a non-negative integer.")),
+    }
+}
+
+/// Resolves an index into a list of `len` items, counting back from the end
+/// when it's negative.
+pub fn list_index(index: &Value, len: usize) -> Result<usize, RuntimeError> {
+    match position(index, len)? {
+        index if index < len as f64 => Ok(index as usize),
+        _ => Err(RuntimeError::new("List index out of range.")),
+    }
+}
+
+/// Like `list_index`, but also allows the position just past the end.
+fn insert_index(index: &Value, len: usize) -> Result<usize, RuntimeError> {
+    match position(index, len)? {
+        index if index <= len as f64 => Ok(index as usize),
+        _ => Err(RuntimeError::new("List index out of range.")),
+    }
+}
+
+fn position(index: &Value, len: usize) -> Result<f64, RuntimeError> {
+    let index = match index {
+        Value::Number(n) if n.fract() == 0.0 => *n,
+        _ => return Err(RuntimeError::new("List index must be an integer.")),
+    };
+    let index = if index < 0.0 { index + len as f64 } else { index };
+    if index < 0.0 {
+        return Err(RuntimeError::new("List index out of range."));
+    }
+    Ok(index)
+}
+
+fn list_arg(heap: &Heap, value: &Value) -> Result<ObjectId, RuntimeError> {
+    match value {
+        Value::Object(id) if heap.get_list(id).is_some() => Ok(*id),
+        _ => Err(RuntimeError::new("First argument must be a list.")),
+    }
+}
+
+/// The number of items in a list, entries in a map or characters in a
+/// string.
+fn len(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let len = match args[0] {
+        Value::Object(id) => match heap.get(&id) {
+            Some(Object::List(items)) => Some(items.len()),
+            Some(Object::Map(map)) => Some(map.len()),
+            Some(Object::String(s)) => Some(s.chars().count()),
+            _ => None,
+        },
+        _ => None,
+    };
+    match len {
+        Some(len) => Ok(Value::from(len as f64)),
+        None => Err(RuntimeError::new("Argument must be a list, a map or a string.")),
+    }
+}
+
+fn push(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = list_arg(heap, &args[0])?;
+    heap.get_list_mut(&list).unwrap().push(args[1]);
+    Ok(Value::Nil)
+}
+
+fn pop(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = list_arg(heap, &args[0])?;
+    match heap.get_list_mut(&list).unwrap().pop() {
+        Some(value) => Ok(value),
+        None => Err(RuntimeError::new("Can't pop from an empty list.")),
+    }
+}
+
+/// Inserts before the item at an index, or at the end when the index is the
+/// list's length.
+fn insert(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = list_arg(heap, &args[0])?;
+    let items = heap.get_list_mut(&list).unwrap();
+    let index = insert_index(&args[1], items.len())?;
+    items.insert(index, args[2]);
+    Ok(Value::Nil)
+}
+
+/// Removes the item at an index and returns it.
+fn remove(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let list = list_arg(heap, &args[0])?;
+    let items = heap.get_list_mut(&list).unwrap();
+    let index = list_index(&args[1], items.len())?;
+    Ok(items.remove(index))
+}
+
+/// A new list of the items from `start` up to but not including `end`, or
+/// to the end of the list when there's no `end`. Negative bounds count from
+/// the end and bounds past either end are clamped.
+fn slice(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args.len() > 3 {
+        return Err(RuntimeError::new(&format!(
+            "Expected 2 or 3 arguments but got {}.",
+            args.len()
+        )));
+    }
+    let list = list_arg(heap, &args[0])?;
+    let items = heap.get_list(&list).unwrap();
+    let len = items.len() as f64;
+    let bound = |value: &Value| match value {
+        Value::Number(n) if n.fract() == 0.0 => {
+            let n = if *n < 0.0 { n + len } else { *n };
+            Ok(n.clamp(0.0, len) as usize)
+        }
+        _ => Err(RuntimeError::new("List index must be an integer.")),
+    };
+    let start = bound(&args[1])?;
+    let end = match args.get(2) {
+        Some(end) => bound(end)?,
+        None => items.len(),
+    };
+    let sliced = items[start..end.max(start)].to_vec();
+    Ok(Value::Object(heap.new_list(sliced)))
+}
+
+fn map_arg(heap: &Heap, value: &Value) -> Result<ObjectId, RuntimeError> {
+    match value {
+        Value::Object(id) if heap.get_map(id).is_some() => Ok(*id),
+        _ => Err(RuntimeError::new("First argument must be a map.")),
+    }
+}
+
+fn has(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let map = map_arg(heap, &args[0])?;
+    let key = MapKey::new(&args[1], heap)?;
+    Ok(Value::Bool(heap.get_map(&map).unwrap().contains(&key)))
+}
+
+/// Removes a key, returning whether it was there.
+fn delete(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let map = map_arg(heap, &args[0])?;
+    let key = MapKey::new(&args[1], heap)?;
+    Ok(Value::Bool(heap.get_map_mut(&map).unwrap().remove(&key).is_some()))
+}
+
+/// A new list of the map's keys, in the order they were added.
+fn keys(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let map = map_arg(heap, &args[0])?;
+    let keys = heap.get_map(&map).unwrap().entries().iter().map(|(key, _)| *key).collect();
+    Ok(Value::Object(heap.new_list(keys)))
+}
+
+/// A new list of the map's values, in the same order as `keys`.
+fn values(heap: &mut Heap, args: &[Value]) -> Result<Value, RuntimeError> {
+    let map = map_arg(heap, &args[0])?;
+    let values = heap.get_map(&map).unwrap().entries().iter().map(|(_, value)| *value).collect();
+    Ok(Value::Object(heap.new_list(values)))
+}
@@ -1,7 +1,14 @@
-use crate::vm::Chunk;
-use crate::core::Value;
+use crate::vm::{Chunk, Heap};
 
-#[derive(Clone, Copy, Debug)]
+/// A decoded instruction. In a chunk each one is a single opcode byte
+/// followed by its operand, big endian: one byte for most and two for jumps
+/// and list and map sizes.
+/// The `Long` variants are the same instructions with room for bigger
+/// operands, three bytes for constants and two for slots and argument
+/// counts. `Closure` is also followed by a two byte count and that many
+/// `(is_local, index)` descriptors of the upvalues to capture, each a byte
+/// and a two byte index.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OpCode {
     Return,
     Negate,
@@ -31,98 +38,474 @@ pub enum OpCode {
     Loop(usize),
     Call(usize),
     Closure(usize),
-    LocalValue(usize),
-    Upvalue(usize),
+    Class(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    Method(usize),
+    Inherit,
+    GetSuper(usize),
+    CloseUpvalue,
+    ConstantLong(usize),
+    DefineGlobalLong(usize),
+    GetGlobalLong(usize),
+    SetGlobalLong(usize),
+    GetLocalLong(usize),
+    SetLocalLong(usize),
+    GetUpvalueLong(usize),
+    SetUpvalueLong(usize),
+    CallLong(usize),
+    ClosureLong(usize),
+    ClassLong(usize),
+    GetPropertyLong(usize),
+    SetPropertyLong(usize),
+    MethodLong(usize),
+    GetSuperLong(usize),
+    BuildList(usize),
+    BuildMap(usize),
+    GetIndex,
+    SetIndex,
+    /// Registers a handler that catches anything thrown until the matching
+    /// `PopHandler`, jumping forward like `Jump`.
+    Try(usize),
+    PopHandler,
+    Throw,
+    /// Throws again what a `try` caught once its `finally` has run, keeping
+    /// the error from the original throw.
+    Rethrow,
 }
 
+const RETURN: u8 = 0;
+const NEGATE: u8 = 1;
+const ADD: u8 = 2;
+const SUBTRACT: u8 = 3;
+const MULTIPLY: u8 = 4;
+const DIVIDE: u8 = 5;
+const NOT: u8 = 6;
+const NIL: u8 = 7;
+const TRUE: u8 = 8;
+const FALSE: u8 = 9;
+const EQUAL: u8 = 10;
+const GREATER: u8 = 11;
+const LESS: u8 = 12;
+const PRINT: u8 = 13;
+const POP: u8 = 14;
+const CONSTANT: u8 = 15;
+const DEFINE_GLOBAL: u8 = 16;
+const GET_GLOBAL: u8 = 17;
+const SET_GLOBAL: u8 = 18;
+const GET_LOCAL: u8 = 19;
+const SET_LOCAL: u8 = 20;
+const GET_UPVALUE: u8 = 21;
+const SET_UPVALUE: u8 = 22;
+const JUMP_IF_FALSE: u8 = 23;
+const JUMP: u8 = 24;
+const LOOP: u8 = 25;
+const CALL: u8 = 26;
+const CLOSURE: u8 = 27;
+const CLASS: u8 = 28;
+const GET_PROPERTY: u8 = 29;
+const SET_PROPERTY: u8 = 30;
+const METHOD: u8 = 31;
+const INHERIT: u8 = 32;
+const GET_SUPER: u8 = 33;
+const CLOSE_UPVALUE: u8 = 34;
+const CONSTANT_LONG: u8 = 35;
+const DEFINE_GLOBAL_LONG: u8 = 36;
+const GET_GLOBAL_LONG: u8 = 37;
+const SET_GLOBAL_LONG: u8 = 38;
+const GET_LOCAL_LONG: u8 = 39;
+const SET_LOCAL_LONG: u8 = 40;
+const GET_UPVALUE_LONG: u8 = 41;
+const SET_UPVALUE_LONG: u8 = 42;
+const CALL_LONG: u8 = 43;
+const CLOSURE_LONG: u8 = 44;
+const CLASS_LONG: u8 = 45;
+const GET_PROPERTY_LONG: u8 = 46;
+const SET_PROPERTY_LONG: u8 = 47;
+const METHOD_LONG: u8 = 48;
+const GET_SUPER_LONG: u8 = 49;
+const BUILD_LIST: u8 = 50;
+const GET_INDEX: u8 = 51;
+const SET_INDEX: u8 = 52;
+const BUILD_MAP: u8 = 53;
+const TRY: u8 = 54;
+const POP_HANDLER: u8 = 55;
+const THROW: u8 = 56;
+const RETHROW: u8 = 57;
+
+/// The size of a jump instruction, which jump offsets are measured from.
+pub const JUMP_SIZE: usize = 3;
+/// The size of one upvalue descriptor trailing a `Closure`.
+pub const UPVALUE_SIZE: usize = 3;
+
 impl OpCode {
-    pub fn disassemble_instruction(op_code: &OpCode, chunk: &Chunk, offset: usize) {
-        let mut prefix = format!("{:04}\t", offset);
-        let line_number = match chunk.lines.get(offset) {
-            Some(line) => {
-                if offset > 0 && line == chunk.lines.get(offset - 1).unwrap() {
-                    format!("   |")
-                } else {
-                    format!("{:04}", line)
-                }
-            }
-            None => {
-                "1".to_string() // shouldn't ever happen
+    /// The largest operand each instruction can encode.
+    pub fn max_operand(&self) -> usize {
+        match self.parts().2 {
+            0 => 0,
+            width => (1 << (8 * width)) - 1,
+        }
+    }
+
+    /// The long form of the instruction if its operand doesn't fit in the
+    /// short one.
+    pub fn widen(self) -> OpCode {
+        if self.parts().1 <= self.max_operand() {
+            return self;
+        }
+        match self {
+            OpCode::Constant(n) => OpCode::ConstantLong(n),
+            OpCode::DefineGlobal(n) => OpCode::DefineGlobalLong(n),
+            OpCode::GetGlobal(n) => OpCode::GetGlobalLong(n),
+            OpCode::SetGlobal(n) => OpCode::SetGlobalLong(n),
+            OpCode::GetLocal(n) => OpCode::GetLocalLong(n),
+            OpCode::SetLocal(n) => OpCode::SetLocalLong(n),
+            OpCode::GetUpvalue(n) => OpCode::GetUpvalueLong(n),
+            OpCode::SetUpvalue(n) => OpCode::SetUpvalueLong(n),
+            OpCode::Call(n) => OpCode::CallLong(n),
+            OpCode::Closure(n) => OpCode::ClosureLong(n),
+            OpCode::Class(n) => OpCode::ClassLong(n),
+            OpCode::GetProperty(n) => OpCode::GetPropertyLong(n),
+            OpCode::SetProperty(n) => OpCode::SetPropertyLong(n),
+            OpCode::Method(n) => OpCode::MethodLong(n),
+            OpCode::GetSuper(n) => OpCode::GetSuperLong(n),
+            op_code => op_code,
+        }
+    }
+
+    /// Appends the instruction's bytes to `code`. The upvalue descriptors of a
+    /// `Closure` are written by `encode_upvalues`.
+    pub fn encode(&self, code: &mut Vec<u8>) {
+        let (byte, operand, width) = self.parts();
+        debug_assert!(operand <= self.max_operand(), "{:?} doesn't fit its operand", self);
+        code.push(byte);
+        push_operand(code, operand, width);
+    }
+
+    pub fn encode_upvalues(code: &mut Vec<u8>, upvalues: &[(bool, usize)]) {
+        push_operand(code, upvalues.len(), 2);
+        for (local, index) in upvalues {
+            code.push(*local as u8);
+            push_operand(code, *index, 2);
+        }
+    }
+
+    /// Where the upvalue descriptors of the closure at `offset` start and
+    /// how many there are.
+    pub fn upvalue_descriptors(code: &[u8], offset: usize) -> Option<(usize, usize)> {
+        let count_at = match *code.get(offset)? {
+            CLOSURE => offset + 2,
+            CLOSURE_LONG => offset + 4,
+            _ => return None,
+        };
+        let count = read_operand(code, count_at, 2)?;
+        Some((count_at + 2, count))
+    }
+
+    /// Decodes the instruction at `offset`, returning it along with the
+    /// offset of the next one, or `None` if the bytes there aren't a whole
+    /// instruction.
+    pub fn decode(code: &[u8], offset: usize) -> Option<(OpCode, usize)> {
+        let byte = *code.get(offset)?;
+        let operand = |width: usize| read_operand(code, offset + 1, width);
+        let (op_code, width) = match byte {
+            RETURN => (OpCode::Return, 0),
+            NEGATE => (OpCode::Negate, 0),
+            ADD => (OpCode::Add, 0),
+            SUBTRACT => (OpCode::Subtract, 0),
+            MULTIPLY => (OpCode::Multiply, 0),
+            DIVIDE => (OpCode::Divide, 0),
+            NOT => (OpCode::Not, 0),
+            NIL => (OpCode::Nil, 0),
+            TRUE => (OpCode::True, 0),
+            FALSE => (OpCode::False, 0),
+            EQUAL => (OpCode::Equal, 0),
+            GREATER => (OpCode::Greater, 0),
+            LESS => (OpCode::Less, 0),
+            PRINT => (OpCode::Print, 0),
+            POP => (OpCode::Pop, 0),
+            INHERIT => (OpCode::Inherit, 0),
+            CLOSE_UPVALUE => (OpCode::CloseUpvalue, 0),
+            GET_INDEX => (OpCode::GetIndex, 0),
+            SET_INDEX => (OpCode::SetIndex, 0),
+            POP_HANDLER => (OpCode::PopHandler, 0),
+            THROW => (OpCode::Throw, 0),
+            RETHROW => (OpCode::Rethrow, 0),
+            CONSTANT => (OpCode::Constant(operand(1)?), 1),
+            DEFINE_GLOBAL => (OpCode::DefineGlobal(operand(1)?), 1),
+            GET_GLOBAL => (OpCode::GetGlobal(operand(1)?), 1),
+            SET_GLOBAL => (OpCode::SetGlobal(operand(1)?), 1),
+            GET_LOCAL => (OpCode::GetLocal(operand(1)?), 1),
+            SET_LOCAL => (OpCode::SetLocal(operand(1)?), 1),
+            GET_UPVALUE => (OpCode::GetUpvalue(operand(1)?), 1),
+            SET_UPVALUE => (OpCode::SetUpvalue(operand(1)?), 1),
+            CALL => (OpCode::Call(operand(1)?), 1),
+            CLASS => (OpCode::Class(operand(1)?), 1),
+            GET_PROPERTY => (OpCode::GetProperty(operand(1)?), 1),
+            SET_PROPERTY => (OpCode::SetProperty(operand(1)?), 1),
+            METHOD => (OpCode::Method(operand(1)?), 1),
+            GET_SUPER => (OpCode::GetSuper(operand(1)?), 1),
+            JUMP_IF_FALSE => (OpCode::JumpIfFalse(operand(2)?), 2),
+            JUMP => (OpCode::Jump(operand(2)?), 2),
+            LOOP => (OpCode::Loop(operand(2)?), 2),
+            TRY => (OpCode::Try(operand(2)?), 2),
+            BUILD_LIST => (OpCode::BuildList(operand(2)?), 2),
+            BUILD_MAP => (OpCode::BuildMap(operand(2)?), 2),
+            CONSTANT_LONG => (OpCode::ConstantLong(operand(3)?), 3),
+            DEFINE_GLOBAL_LONG => (OpCode::DefineGlobalLong(operand(3)?), 3),
+            GET_GLOBAL_LONG => (OpCode::GetGlobalLong(operand(3)?), 3),
+            SET_GLOBAL_LONG => (OpCode::SetGlobalLong(operand(3)?), 3),
+            GET_LOCAL_LONG => (OpCode::GetLocalLong(operand(2)?), 2),
+            SET_LOCAL_LONG => (OpCode::SetLocalLong(operand(2)?), 2),
+            GET_UPVALUE_LONG => (OpCode::GetUpvalueLong(operand(2)?), 2),
+            SET_UPVALUE_LONG => (OpCode::SetUpvalueLong(operand(2)?), 2),
+            CALL_LONG => (OpCode::CallLong(operand(2)?), 2),
+            CLASS_LONG => (OpCode::ClassLong(operand(3)?), 3),
+            GET_PROPERTY_LONG => (OpCode::GetPropertyLong(operand(3)?), 3),
+            SET_PROPERTY_LONG => (OpCode::SetPropertyLong(operand(3)?), 3),
+            METHOD_LONG => (OpCode::MethodLong(operand(3)?), 3),
+            GET_SUPER_LONG => (OpCode::GetSuperLong(operand(3)?), 3),
+            CLOSURE | CLOSURE_LONG => {
+                let op_code = match byte {
+                    CLOSURE => OpCode::Closure(operand(1)?),
+                    _ => OpCode::ClosureLong(operand(3)?),
+                };
+                let (start, count) = OpCode::upvalue_descriptors(code, offset)?;
+                (op_code, start + UPVALUE_SIZE * count - offset - 1)
             }
+            _ => return None,
         };
-        prefix.push_str(&line_number);
+        let next = offset + 1 + width;
+        if next > code.len() {
+            return None;
+        }
+        Some((op_code, next))
+    }
+
+    /// The opcode byte, the operand and how many bytes the operand takes.
+    fn parts(&self) -> (u8, usize, usize) {
+        match *self {
+            OpCode::Return => (RETURN, 0, 0),
+            OpCode::Negate => (NEGATE, 0, 0),
+            OpCode::Add => (ADD, 0, 0),
+            OpCode::Subtract => (SUBTRACT, 0, 0),
+            OpCode::Multiply => (MULTIPLY, 0, 0),
+            OpCode::Divide => (DIVIDE, 0, 0),
+            OpCode::Not => (NOT, 0, 0),
+            OpCode::Nil => (NIL, 0, 0),
+            OpCode::True => (TRUE, 0, 0),
+            OpCode::False => (FALSE, 0, 0),
+            OpCode::Equal => (EQUAL, 0, 0),
+            OpCode::Greater => (GREATER, 0, 0),
+            OpCode::Less => (LESS, 0, 0),
+            OpCode::Print => (PRINT, 0, 0),
+            OpCode::Pop => (POP, 0, 0),
+            OpCode::Inherit => (INHERIT, 0, 0),
+            OpCode::CloseUpvalue => (CLOSE_UPVALUE, 0, 0),
+            OpCode::GetIndex => (GET_INDEX, 0, 0),
+            OpCode::SetIndex => (SET_INDEX, 0, 0),
+            OpCode::PopHandler => (POP_HANDLER, 0, 0),
+            OpCode::Throw => (THROW, 0, 0),
+            OpCode::Rethrow => (RETHROW, 0, 0),
+            OpCode::Constant(n) => (CONSTANT, n, 1),
+            OpCode::DefineGlobal(n) => (DEFINE_GLOBAL, n, 1),
+            OpCode::GetGlobal(n) => (GET_GLOBAL, n, 1),
+            OpCode::SetGlobal(n) => (SET_GLOBAL, n, 1),
+            OpCode::GetLocal(n) => (GET_LOCAL, n, 1),
+            OpCode::SetLocal(n) => (SET_LOCAL, n, 1),
+            OpCode::GetUpvalue(n) => (GET_UPVALUE, n, 1),
+            OpCode::SetUpvalue(n) => (SET_UPVALUE, n, 1),
+            OpCode::Call(n) => (CALL, n, 1),
+            OpCode::Closure(n) => (CLOSURE, n, 1),
+            OpCode::Class(n) => (CLASS, n, 1),
+            OpCode::GetProperty(n) => (GET_PROPERTY, n, 1),
+            OpCode::SetProperty(n) => (SET_PROPERTY, n, 1),
+            OpCode::Method(n) => (METHOD, n, 1),
+            OpCode::GetSuper(n) => (GET_SUPER, n, 1),
+            OpCode::JumpIfFalse(n) => (JUMP_IF_FALSE, n, 2),
+            OpCode::Jump(n) => (JUMP, n, 2),
+            OpCode::Loop(n) => (LOOP, n, 2),
+            OpCode::Try(n) => (TRY, n, 2),
+            OpCode::BuildList(n) => (BUILD_LIST, n, 2),
+            OpCode::BuildMap(n) => (BUILD_MAP, n, 2),
+            OpCode::ConstantLong(n) => (CONSTANT_LONG, n, 3),
+            OpCode::DefineGlobalLong(n) => (DEFINE_GLOBAL_LONG, n, 3),
+            OpCode::GetGlobalLong(n) => (GET_GLOBAL_LONG, n, 3),
+            OpCode::SetGlobalLong(n) => (SET_GLOBAL_LONG, n, 3),
+            OpCode::GetLocalLong(n) => (GET_LOCAL_LONG, n, 2),
+            OpCode::SetLocalLong(n) => (SET_LOCAL_LONG, n, 2),
+            OpCode::GetUpvalueLong(n) => (GET_UPVALUE_LONG, n, 2),
+            OpCode::SetUpvalueLong(n) => (SET_UPVALUE_LONG, n, 2),
+            OpCode::CallLong(n) => (CALL_LONG, n, 2),
+            OpCode::ClosureLong(n) => (CLOSURE_LONG, n, 3),
+            OpCode::ClassLong(n) => (CLASS_LONG, n, 3),
+            OpCode::GetPropertyLong(n) => (GET_PROPERTY_LONG, n, 3),
+            OpCode::SetPropertyLong(n) => (SET_PROPERTY_LONG, n, 3),
+            OpCode::MethodLong(n) => (METHOD_LONG, n, 3),
+            OpCode::GetSuperLong(n) => (GET_SUPER_LONG, n, 3),
+        }
+    }
+
+    pub fn disassemble_instruction(op_code: &OpCode, chunk: &Chunk, offset: usize, heap: &Heap) -> String {
+        let mut prefix = format!("{:04}\t", offset);
+        let line = chunk.line(offset);
+        if offset > 0 && line == chunk.line(offset - 1) {
+            prefix.push_str("   |");
+        } else {
+            prefix.push_str(&format!("{:04}", line));
+        }
         match op_code {
-            OpCode::Return => println!("{} Return", prefix),
-            OpCode::Add => println!("{} Add\t", prefix),
-            OpCode::Subtract => println!("{} Subtract\t", prefix),
-            OpCode::Multiply => println!("{} Multiply\t", prefix),
-            OpCode::Divide => println!("{} Divide\t", prefix),
-            OpCode::Negate => println!("{} Negate\t", prefix),
-            OpCode::Nil => println!("{} Nil", prefix),
-            OpCode::True => println!("{} True", prefix),
-            OpCode::False => println!("{} False", prefix),
-            OpCode::Not => println!("{} Not", prefix),
-            OpCode::Equal => println!("{} Equal", prefix),
-            OpCode::Greater => println!("{} Greater", prefix),
-            OpCode::Less => println!("{} Less", prefix),
-            OpCode::Print => println!("{} Print", prefix),
-            OpCode::Pop => println!("{} Pop", prefix),
-            OpCode::Constant(index) => {
-                if let Some(constant) = chunk.constants.get(*index) {
-                    println!("{} Constant\t{} '{}'", prefix, index, constant);
-                }
-            }
-            OpCode::DefineGlobal(index) => {
-                if let Some(constant) = chunk.constants.get(*index) {
-                    println!("{} DefineGlobal\t{} '{}'", prefix, index, constant);
-                }
-            }
-            OpCode::GetGlobal(index) => {
-                if let Some(constant) = chunk.constants.get(*index) {
-                    println!("{} GetGlobal\t{} '{}'", prefix, index, constant);
+            OpCode::Return => format!("{} Return", prefix),
+            OpCode::Add => format!("{} Add", prefix),
+            OpCode::Subtract => format!("{} Subtract", prefix),
+            OpCode::Multiply => format!("{} Multiply", prefix),
+            OpCode::Divide => format!("{} Divide", prefix),
+            OpCode::Negate => format!("{} Negate", prefix),
+            OpCode::Nil => format!("{} Nil", prefix),
+            OpCode::True => format!("{} True", prefix),
+            OpCode::False => format!("{} False", prefix),
+            OpCode::Not => format!("{} Not", prefix),
+            OpCode::Equal => format!("{} Equal", prefix),
+            OpCode::Greater => format!("{} Greater", prefix),
+            OpCode::Less => format!("{} Less", prefix),
+            OpCode::Print => format!("{} Print", prefix),
+            OpCode::Pop => format!("{} Pop", prefix),
+            OpCode::Constant(index) => constant_instruction(&prefix, "Constant", chunk, *index, heap),
+            OpCode::ConstantLong(index) => constant_instruction(&prefix, "ConstantLong", chunk, *index, heap),
+            OpCode::DefineGlobal(index) => constant_instruction(&prefix, "DefineGlobal", chunk, *index, heap),
+            OpCode::GetGlobal(index) => constant_instruction(&prefix, "GetGlobal", chunk, *index, heap),
+            OpCode::SetGlobal(index) => constant_instruction(&prefix, "SetGlobal", chunk, *index, heap),
+            OpCode::SetLocal(index) => format!("{} SetLocal\t{}", prefix, index),
+            OpCode::GetLocal(index) => format!("{} GetLocal\t{}", prefix, index),
+            OpCode::GetUpvalue(index) => format!("{} GetUpvalue\t{}", prefix, index),
+            OpCode::SetUpvalue(index) => format!("{} SetUpvalue\t{}", prefix, index),
+            OpCode::CloseUpvalue => format!("{} CloseUpvalue", prefix),
+            // Jumps are relative to the next instruction, show where they land
+            OpCode::JumpIfFalse(jmp) => format!("{} JumpIfFalse\t{} -> {}", prefix, offset, offset + JUMP_SIZE + jmp),
+            OpCode::Jump(jmp) => format!("{} Jump\t{} -> {}", prefix, offset, offset + JUMP_SIZE + jmp),
+            OpCode::Loop(jmp) => format!("{} Loop\t{} -> {}", prefix, offset, (offset + JUMP_SIZE).wrapping_sub(*jmp)),
+            OpCode::Call(arg_count) => format!("{} Call arg_count {}", prefix, arg_count),
+            OpCode::Closure(index) | OpCode::ClosureLong(index) => {
+                let name = match op_code {
+                    OpCode::Closure(_) => "Closure",
+                    _ => "ClosureLong",
+                };
+                let mut out = constant_instruction(&prefix, name, chunk, *index, heap);
+                // The upvalues to capture trail the instruction
+                let (start, _) = OpCode::upvalue_descriptors(&chunk.code, offset).unwrap_or_default();
+                for (i, (local, index)) in chunk.upvalues(offset).enumerate() {
+                    let kind = if local { "local" } else { "upvalue" };
+                    out.push_str(&format!("\n{:04}\t   |\t\t{} {}", start + UPVALUE_SIZE * i, kind, index));
                 }
+                out
             }
-            OpCode::SetGlobal(index) => {
-                if let Some(constant) = chunk.constants.get(*index) {
-                    println!("{} SetGlobal\t{} '{}'", prefix, index, constant);
-                }
-            }
-            OpCode::SetLocal(index) => {
-                println!("{} SetLocal\t{}", prefix, index);
-            }
-            OpCode::GetLocal(index) => {
-                println!("{} GetLocal\t{}", prefix, index);
-            }
-            OpCode::GetUpvalue(index) => {
-                println!("{} SetLocal\t{}", prefix, index);
-            }
-            OpCode::SetUpvalue(index) => {
-                println!("{} GetLocal\t{}", prefix, index);
-            }
-            OpCode::JumpIfFalse(jmp) => println!("{} JumpIfFalse offset {}", prefix, jmp),
-            OpCode::Jump(jmp) => println!("{} Jump offset {}", prefix, jmp),
-            OpCode::Loop(jmp) => println!("{} Loop offset {}", prefix, jmp),
-            OpCode::Call(arg_count) => println!("{} Call arg_count {}", prefix, arg_count),
-            OpCode::Closure(index) => {
-                if let Some(constant) = chunk.constants.get(*index) {
-                    if let Value::Object(closure) = constant {
-                        println!("{} Closure", prefix)
-/*                         for _ in 0..(closure.function.upvalue_count + 1) {
-                            if upvalue.local() {
-                                println!("{} Local value\t{} '{}'", prefix, index, constant);
-                            } else {
-                                println!("{} Upvalue\t{} '{}'", prefix, index, constant);
-                            }
-                        } */
-                    }
-                }
-            }
-            OpCode::LocalValue(index) => {
-                println!("{} Local value {}", prefix, index)
-            }
-            OpCode::Upvalue(index) => {
-                println!("{} Upvalue {}", prefix, index)
-            }
+            OpCode::Class(index) => constant_instruction(&prefix, "Class", chunk, *index, heap),
+            OpCode::GetProperty(index) => constant_instruction(&prefix, "GetProperty", chunk, *index, heap),
+            OpCode::SetProperty(index) => constant_instruction(&prefix, "SetProperty", chunk, *index, heap),
+            OpCode::Method(index) => constant_instruction(&prefix, "Method", chunk, *index, heap),
+            OpCode::Inherit => format!("{} Inherit", prefix),
+            OpCode::GetSuper(index) => constant_instruction(&prefix, "GetSuper", chunk, *index, heap),
+            OpCode::DefineGlobalLong(index) => constant_instruction(&prefix, "DefineGlobalLong", chunk, *index, heap),
+            OpCode::GetGlobalLong(index) => constant_instruction(&prefix, "GetGlobalLong", chunk, *index, heap),
+            OpCode::SetGlobalLong(index) => constant_instruction(&prefix, "SetGlobalLong", chunk, *index, heap),
+            OpCode::GetLocalLong(index) => format!("{} GetLocalLong\t{}", prefix, index),
+            OpCode::SetLocalLong(index) => format!("{} SetLocalLong\t{}", prefix, index),
+            OpCode::GetUpvalueLong(index) => format!("{} GetUpvalueLong\t{}", prefix, index),
+            OpCode::SetUpvalueLong(index) => format!("{} SetUpvalueLong\t{}", prefix, index),
+            OpCode::CallLong(arg_count) => format!("{} CallLong arg_count {}", prefix, arg_count),
+            OpCode::ClassLong(index) => constant_instruction(&prefix, "ClassLong", chunk, *index, heap),
+            OpCode::GetPropertyLong(index) => constant_instruction(&prefix, "GetPropertyLong", chunk, *index, heap),
+            OpCode::SetPropertyLong(index) => constant_instruction(&prefix, "SetPropertyLong", chunk, *index, heap),
+            OpCode::MethodLong(index) => constant_instruction(&prefix, "MethodLong", chunk, *index, heap),
+            OpCode::GetSuperLong(index) => constant_instruction(&prefix, "GetSuperLong", chunk, *index, heap),
+            OpCode::BuildList(count) => format!("{} BuildList\t{}", prefix, count),
+            OpCode::BuildMap(count) => format!("{} BuildMap\t{}", prefix, count),
+            OpCode::GetIndex => format!("{} GetIndex", prefix),
+            OpCode::SetIndex => format!("{} SetIndex", prefix),
+            OpCode::Try(jmp) => format!("{} Try\t{} -> {}", prefix, offset, offset + JUMP_SIZE + jmp),
+            OpCode::PopHandler => format!("{} PopHandler", prefix),
+            OpCode::Throw => format!("{} Throw", prefix),
+            OpCode::Rethrow => format!("{} Rethrow", prefix),
         }
     }
 }
+
+fn push_operand(code: &mut Vec<u8>, operand: usize, width: usize) {
+    // The compiler's limits keep operands in range, truncating would
+    // silently corrupt the chunk
+    assert!(operand >> (8 * width) == 0, "Operand {} doesn't fit in {} bytes", operand, width);
+    code.extend_from_slice(&operand.to_be_bytes()[(usize::BITS / 8) as usize - width..]);
+}
+
+fn read_operand(code: &[u8], offset: usize, width: usize) -> Option<usize> {
+    let bytes = code.get(offset..offset + width)?;
+    Some(bytes.iter().fold(0, |operand, byte| operand << 8 | *byte as usize))
+}
+
+fn constant_instruction(prefix: &str, name: &str, chunk: &Chunk, index: usize, heap: &Heap) -> String {
+    match chunk.constants.get(index) {
+        Some(constant) => format!("{} {}\t{} '{}'", prefix, name, index, heap.format_value(constant)),
+        None => format!("{} {}\t{}", prefix, name, index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let op_codes = [
+            OpCode::Return,
+            OpCode::Constant(255),
+            OpCode::ConstantLong(0x01_02_03),
+            OpCode::Jump(0xBEEF),
+            OpCode::GetLocal(7),
+            OpCode::Try(0x0102),
+            OpCode::Throw,
+            OpCode::Rethrow,
+        ];
+        let mut code = Vec::new();
+        for op_code in &op_codes {
+            op_code.encode(&mut code);
+        }
+        assert_eq!(code.len(), 1 + 2 + 4 + 3 + 2 + 3 + 1 + 1);
+
+        let mut offset = 0;
+        for op_code in &op_codes {
+            let (decoded, next) = OpCode::decode(&code, offset).unwrap();
+            assert_eq!(decoded, *op_code);
+            offset = next;
+        }
+        assert_eq!(offset, code.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit in 2 bytes")]
+    fn refuses_more_upvalues_than_the_count_holds() {
+        let upvalues = vec![(true, 0); 1 << 16];
+        OpCode::encode_upvalues(&mut Vec::new(), &upvalues);
+    }
+
+    #[test]
+    fn widens_operands_that_dont_fit() {
+        assert_eq!(OpCode::GetGlobal(255).widen(), OpCode::GetGlobal(255));
+        assert_eq!(OpCode::GetGlobal(256).widen(), OpCode::GetGlobalLong(256));
+        assert_eq!(OpCode::Jump(256).widen(), OpCode::Jump(256));
+
+        let mut code = Vec::new();
+        OpCode::ClosureLong(70_000).encode(&mut code);
+        OpCode::encode_upvalues(&mut code, &[(true, 300), (false, 2)]);
+        assert_eq!(OpCode::decode(&code, 0), Some((OpCode::ClosureLong(70_000), code.len())));
+        assert_eq!(OpCode::upvalue_descriptors(&code, 0), Some((6, 2)));
+    }
+
+    #[test]
+    fn rejects_partial_instructions() {
+        let mut code = Vec::new();
+        OpCode::ConstantLong(1).encode(&mut code);
+        code.pop();
+        assert_eq!(OpCode::decode(&code, 0), None);
+        assert_eq!(OpCode::decode(&[255], 0), None);
+    }
+}
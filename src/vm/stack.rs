@@ -43,11 +43,22 @@ impl Stack {
         self.stack.pop().unwrap()
     }
 
+    /// Drops every value from `top` up.
+    pub fn truncate(&mut self, top: usize) {
+        self.stack.truncate(top);
+        self.top = top;
+    }
+
     pub fn peek(&self, distance: usize) -> &Value {
         let peek_index = self.top - distance - 1;
         &self.stack[peek_index]
     }
 
+    /// The top `count` values, oldest first.
+    pub fn peek_many(&self, count: usize) -> &[Value] {
+        &self.stack[(self.top - count)..self.top]
+    }
+
     pub fn drain<R>(&mut self, range: R) -> Drain<'_, Value>
     where
         R: RangeBounds<usize>,
@@ -56,11 +67,9 @@ impl Stack {
         result
     }
 
-    pub fn print_stack(&self) {
-        println!("======== STACK =======");
-        for i in 0..self.top {
-            println!("[{}]", self.stack[i]);
-        }
+    /// The live values, bottom first.
+    pub fn as_slice(&self) -> &[Value] {
+        &self.stack[..self.top]
     }
 }
 
@@ -0,0 +1,93 @@
+use std::io::Write;
+
+/// The kinds of events a `Tracer` can ask the VM for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceKind {
+    /// The bytecode of each function, once it has been compiled.
+    Code,
+    /// Every instruction as it executes, with the stack before it.
+    Exec,
+    /// Every call and return.
+    Calls,
+}
+
+/// Receives execution events from the VM. The VM only formats an event when
+/// `wants` says it is needed, so an idle tracer costs next to nothing.
+pub trait Tracer {
+    /// Whether to report `kind` events that happen inside `function`. The
+    /// top level script is called `script`.
+    fn wants(&self, kind: TraceKind, function: &str) -> bool;
+
+    fn code(&mut self, _function: &str, _listing: &str) {}
+
+    fn instruction(&mut self, _function: &str, _instruction: &str, _stack: &[String]) {}
+
+    fn call(&mut self, _function: &str, _depth: usize) {}
+
+    fn ret(&mut self, _function: &str, _depth: usize, _value: &str) {}
+}
+
+/// Which events a `WriteTracer` reports.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceOptions {
+    pub code: bool,
+    pub exec: bool,
+    pub calls: bool,
+    /// Only report events inside the function with this name.
+    pub function: Option<String>,
+}
+
+impl TraceOptions {
+    pub fn any(&self) -> bool {
+        self.code || self.exec || self.calls
+    }
+}
+
+/// A `Tracer` that writes plain text to any writer, such as stderr or a
+/// file.
+pub struct WriteTracer {
+    out: Box<dyn Write>,
+    options: TraceOptions,
+}
+
+impl WriteTracer {
+    pub fn new(out: Box<dyn Write>, options: TraceOptions) -> Self {
+        WriteTracer { out, options }
+    }
+}
+
+impl Tracer for WriteTracer {
+    fn wants(&self, kind: TraceKind, function: &str) -> bool {
+        let enabled = match kind {
+            TraceKind::Code => self.options.code,
+            TraceKind::Exec => self.options.exec,
+            TraceKind::Calls => self.options.calls,
+        };
+        enabled && self.options.function.as_ref().is_none_or(|f| f == function)
+    }
+
+    // Tracing is best effort, a failed write shouldn't stop the script
+    fn code(&mut self, _function: &str, listing: &str) {
+        let _ = write!(self.out, "{}", listing);
+    }
+
+    fn instruction(&mut self, _function: &str, instruction: &str, stack: &[String]) {
+        let stack: Vec<String> = stack.iter().map(|value| format!("[ {} ]", value)).collect();
+        let _ = writeln!(self.out, "          {}", stack.join(""));
+        let _ = writeln!(self.out, "{}", instruction);
+    }
+
+    fn call(&mut self, function: &str, depth: usize) {
+        let _ = writeln!(self.out, "{}-> {}()", "  ".repeat(depth.saturating_sub(1)), function);
+    }
+
+    fn ret(&mut self, function: &str, depth: usize, value: &str) {
+        let _ = writeln!(
+            self.out,
+            "{}<- {}() = {}",
+            "  ".repeat(depth.saturating_sub(1)),
+            function,
+            value
+        );
+    }
+}